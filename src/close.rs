@@ -0,0 +1,539 @@
+//! Closing connections: close codes, [`Disconnect`], shutting down on exit and dropping
+//! stalled connections.
+
+use std::time::Duration;
+
+use bevy::prelude::*;
+use thiserror::Error;
+
+use crate::{
+    write_next, ConnectionClosed, ConnectionState, DryRun, MaxOutFrameSize, Outbox,
+    OutboxMemoryBudget, ResendQueuedOnReconnect, SendOutcome, WebSocketClient,
+};
+
+#[cfg(not(target_arch = "wasm32"))]
+use crate::tcp_stream;
+#[cfg(target_arch = "wasm32")]
+use crate::{heartbeat_frame, ping_payload, HeartbeatKind};
+#[cfg(not(target_arch = "wasm32"))]
+use std::io::ErrorKind;
+#[cfg(not(target_arch = "wasm32"))]
+use tungstenite::Message;
+
+/// Close codes from RFC 6455, section 7.4.1
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CloseCode {
+    /// 1000, the connection did what it was for
+    Normal,
+    /// 1001, e.g. the app is shutting down
+    Away,
+    /// 1002
+    Protocol,
+    /// 1003, the peer sent a kind of data we can't accept
+    Unsupported,
+    /// 1007, the payload didn't match its message type
+    Invalid,
+    /// 1008
+    Policy,
+    /// 1009
+    Size,
+    /// 1011, something unexpected went wrong on our end
+    Error,
+    /// Application specific codes, see [`CloseCode::other`]
+    Other(AppCloseCode),
+}
+
+/// A close code from the 3000 to 4999 range RFC 6455 leaves to libraries and applications
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AppCloseCode(u16);
+
+#[derive(Error, Debug, PartialEq, Eq)]
+#[error("close code {0} is outside the application range of 3000 to 4999")]
+pub struct InvalidCloseCode(pub u16);
+
+impl CloseCode {
+    /// An application specific code, rejecting those the RFC reserves or leaves undefined
+    pub fn other(code: u16) -> Result<Self, InvalidCloseCode> {
+        match code {
+            3000..=4999 => Ok(CloseCode::Other(AppCloseCode(code))),
+            _ => Err(InvalidCloseCode(code)),
+        }
+    }
+}
+
+impl From<CloseCode> for u16 {
+    fn from(code: CloseCode) -> u16 {
+        match code {
+            CloseCode::Normal => 1000,
+            CloseCode::Away => 1001,
+            CloseCode::Protocol => 1002,
+            CloseCode::Unsupported => 1003,
+            CloseCode::Invalid => 1007,
+            CloseCode::Policy => 1008,
+            CloseCode::Size => 1009,
+            CloseCode::Error => 1011,
+            CloseCode::Other(AppCloseCode(code)) => code,
+        }
+    }
+}
+
+/// Close a connection on purpose, it won't be reconnected
+#[derive(Event)]
+pub struct Disconnect {
+    pub entity: Entity,
+    pub code: CloseCode,
+    pub reason: String,
+}
+
+/// Treat anything this binary-only protocol doesn't expect as a violation and close the
+/// connection with [`CloseCode::Protocol`]: text messages (native only, browsers can't
+/// be that strict) and pongs for pings we never sent. Off by default, text messages are
+/// received as their UTF-8 bytes then.
+#[derive(Resource, Default)]
+pub struct StrictProtocol(pub bool);
+
+/// Marks a connection we're closing, nothing more is sent on it
+#[derive(Component)]
+pub struct Closing {
+    /// [`Time::elapsed`] when we sent our close frame
+    since: Duration,
+}
+
+/// How long to wait for the server to answer our close frame before dropping the socket anyway
+#[derive(Resource)]
+pub struct CloseTimeout(pub Duration);
+
+pub fn disconnect(
+    mut commands: Commands,
+    mut ev_disconnect: EventReader<Disconnect>,
+    mut clients: Query<&mut WebSocketClient, Without<Closing>>,
+    time: Res<Time>,
+    #[cfg(target_arch = "wasm32")] mut ev_closed: EventWriter<ConnectionClosed>,
+) {
+    for Disconnect {
+        entity,
+        code,
+        reason,
+    } in ev_disconnect.read()
+    {
+        let Ok(mut client) = clients.get_mut(*entity) else {
+            warn!("Not disconnecting {entity}: it has no open connection");
+            continue;
+        };
+        info!("Disconnecting {entity} ({code:?}: {reason})");
+        client.close_with(*code, reason);
+        commands.entity(*entity).insert((
+            Closing {
+                since: time.elapsed(),
+            },
+            ConnectionState::Closing,
+        ));
+        // natively `recv_info` drives the handshake to completion, the browser does that for us
+        #[cfg(target_arch = "wasm32")]
+        {
+            commands
+                .entity(*entity)
+                .remove::<(WebSocketClient, Outbox)>()
+                .insert(ConnectionState::Disconnected);
+            let ev = ConnectionClosed {
+                entity: *entity,
+                reason: reason.clone(),
+            };
+            commands.trigger_targets(ev.clone(), *entity);
+            ev_closed.send(ev);
+        }
+    }
+}
+
+/// Systems closing every connection when the app exits. They run in [`Last`] of the frame
+/// [`AppExit`] is sent, before the world and with it the sockets are dropped, so order other
+/// teardown after this set. An `AppExit` sent later in `Last` than this set isn't seen.
+#[derive(SystemSet, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct NetworkShutdown;
+
+/// How long [`close_on_exit`] blocks, over all connections, to get queued messages and the
+/// close frames out natively. Browsers finish the close handshake on their own.
+#[cfg(not(target_arch = "wasm32"))]
+const SHUTDOWN_FLUSH_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Send what's still queued and close every connection, so peers hear about the shutdown
+pub fn close_on_exit(
+    mut ev_exit: EventReader<AppExit>,
+    mut q: Query<(Entity, &mut WebSocketClient, Option<&mut Outbox>)>,
+    mut budget: ResMut<OutboxMemoryBudget>,
+    max_frame_size: Res<MaxOutFrameSize>,
+    dry_run: Res<DryRun>,
+) {
+    if ev_exit.read().last().is_none() {
+        return;
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    let deadline = std::time::Instant::now() + SHUTDOWN_FLUSH_TIMEOUT;
+    for (entity, mut client, outbox) in &mut q {
+        // block for what's left of the timeout instead, so the writes below go out in full
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            let left = deadline.saturating_duration_since(std::time::Instant::now());
+            let socket = client.socket();
+            // other streams never come out of `connect` or `accept`
+            if let Some(stream) = tcp_stream(socket.get_ref()) {
+                if let Err(e) = stream
+                    .set_nonblocking(false)
+                    .and_then(|_| {
+                        stream.set_write_timeout(Some(left.max(Duration::from_millis(1))))
+                    })
+                    // a blocking read would keep a `ReadThread` from ever letting go of the socket
+                    .and_then(|_| stream.set_read_timeout(Some(Duration::from_millis(1))))
+                {
+                    warn!("Could not wait for {entity} to send everything: {e:?}");
+                }
+            }
+        }
+        if let Some(mut outbox) = outbox {
+            // best effort, whatever doesn't go out before the timeout is lost
+            while let Some((SendOutcome::Sent | SendOutcome::Queued | SendOutcome::DryRun, _)) =
+                write_next(
+                    &mut client,
+                    &mut outbox,
+                    &mut budget,
+                    max_frame_size.0,
+                    // the browser still sends its buffer after we're gone
+                    usize::MAX,
+                    dry_run.0,
+                )
+            {}
+        }
+        client.close_with(CloseCode::Away, "shutting down");
+        #[cfg(not(target_arch = "wasm32"))]
+        match client.socket().flush() {
+            Ok(_) | Err(tungstenite::Error::ConnectionClosed) => {}
+            Err(e) => warn!("Could not flush the close frame to {entity}: {e:?}"),
+        }
+        info!("Closed {entity} for shutdown");
+    }
+}
+
+/// Close every open connection with [`CloseCode::Normal`], e.g. when leaving a screen
+pub fn disconnect_all(
+    connections: Query<Entity, (With<WebSocketClient>, Without<Closing>)>,
+    mut ev_disconnect: EventWriter<Disconnect>,
+) {
+    ev_disconnect.send_batch(connections.iter().map(|entity| Disconnect {
+        entity,
+        code: CloseCode::Normal,
+        reason: "disconnected by the user".to_owned(),
+    }));
+}
+
+/// Drop connections whose server never completed the close handshake
+pub fn expire_closing(
+    mut commands: Commands,
+    closing: Query<(Entity, &Closing), With<WebSocketClient>>,
+    time: Res<Time>,
+    timeout: Res<CloseTimeout>,
+    mut ev_closed: EventWriter<ConnectionClosed>,
+) {
+    for (entity, Closing { since }) in &closing {
+        if time.elapsed().saturating_sub(*since) < timeout.0 {
+            continue;
+        }
+        commands
+            .entity(entity)
+            .remove::<(WebSocketClient, Outbox)>()
+            .insert(ConnectionState::Disconnected);
+        let ev = ConnectionClosed {
+            entity,
+            reason: "close timeout".to_owned(),
+        };
+        commands.trigger_targets(ev.clone(), entity);
+        ev_closed.send(ev);
+    }
+}
+
+/// When to worry about a connection that neither reads nor writes anything, which often
+/// means it died without the OS noticing yet
+#[derive(Resource)]
+pub struct StallTimeout {
+    /// Ping the peer after this long without I/O to provoke an answer
+    pub probe_after: Duration,
+    /// Declare the connection dead after this long without I/O
+    pub dead_after: Duration,
+}
+
+impl Default for StallTimeout {
+    fn default() -> Self {
+        Self {
+            probe_after: Duration::from_secs(10),
+            dead_after: Duration::from_secs(20),
+        }
+    }
+}
+
+/// When a connection last read or wrote something
+#[derive(Component)]
+pub struct IoActivity {
+    /// [`Time::elapsed`] of the last successful read or write
+    pub(crate) last: Duration,
+    /// Whether we already pinged the peer during this stall
+    probed: bool,
+}
+
+/// Heartbeat id of the pings [`detect_stalls`] sends, they aren't tracked by [`Heartbeat`]
+pub(crate) const STALL_PROBE_ID: u32 = u32::MAX;
+
+#[allow(clippy::type_complexity)]
+#[cfg_attr(not(target_arch = "wasm32"), allow(unused_variables, unused_mut))]
+pub fn detect_stalls(
+    mut commands: Commands,
+    mut q: Query<
+        (
+            Entity,
+            &mut WebSocketClient,
+            Option<&mut IoActivity>,
+            Option<&mut Outbox>,
+        ),
+        Without<Closing>,
+    >,
+    time: Res<Time>,
+    timeout: Res<StallTimeout>,
+    mut budget: ResMut<OutboxMemoryBudget>,
+    resend_queued: Res<ResendQueuedOnReconnect>,
+    mut ev_closed: EventWriter<ConnectionClosed>,
+) {
+    let now = time.elapsed();
+    for (entity, mut client, activity, outbox) in &mut q {
+        let fresh = IoActivity {
+            last: now,
+            probed: false,
+        };
+        let Some(mut activity) = activity else {
+            commands.entity(entity).insert(fresh);
+            continue;
+        };
+        if client.is_added() {
+            // reconnected, the old stall doesn't count
+            *activity = fresh;
+            continue;
+        }
+        let idle = now.saturating_sub(activity.last);
+        if idle >= timeout.dead_after {
+            warn!("{entity} had no I/O for {idle:?}, dropping it");
+            client.close_with(CloseCode::Away, "stalled");
+            let mut entity_commands = commands.entity(entity);
+            entity_commands
+                .remove::<WebSocketClient>()
+                .insert(ConnectionState::Disconnected);
+            if !resend_queued.0 {
+                entity_commands.remove::<Outbox>();
+            }
+            let ev = ConnectionClosed {
+                entity,
+                reason: "stalled".to_owned(),
+            };
+            commands.trigger_targets(ev.clone(), entity);
+            ev_closed.send(ev);
+        } else if idle >= timeout.probe_after && !activity.probed {
+            info!("{entity} had no I/O for {idle:?}, probing it");
+            activity.probed = true;
+            #[cfg(not(target_arch = "wasm32"))]
+            match client.socket().write(Message::Ping(Vec::new())) {
+                Ok(_) => {}
+                Err(tungstenite::Error::Io(e)) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => warn!("Could not probe {entity}: {e:?}"),
+            }
+            // browsers can't send pings, but the peer answers application heartbeats
+            #[cfg(target_arch = "wasm32")]
+            if let Some(mut outbox) = outbox {
+                let _ = outbox.push(
+                    heartbeat_frame(HeartbeatKind::Ping, &ping_payload(STALL_PROBE_ID, &[])),
+                    &mut budget,
+                );
+            }
+        } else if idle < timeout.probe_after {
+            activity.probed = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+    use crate::{
+        ping_payload,
+        testing::{app, close_frame_server, drain, read_raw_frame, update_until},
+        ConnectionOpened, SendTo, WebSocketConnectionEvents, WebSocketMessageReceived,
+    };
+
+    #[test]
+    fn only_application_close_codes_are_accepted() {
+        assert_eq!(CloseCode::other(2999), Err(InvalidCloseCode(2999)));
+        assert_eq!(CloseCode::other(3000).map(u16::from), Ok(3000));
+        assert_eq!(CloseCode::other(4999).map(u16::from), Ok(4999));
+        assert_eq!(CloseCode::other(5000), Err(InvalidCloseCode(5000)));
+        assert_eq!(CloseCode::other(1000), Err(InvalidCloseCode(1000)));
+    }
+
+    #[test]
+    fn closing_sends_the_code_and_reason() {
+        let codes = [
+            (CloseCode::Normal, 1000),
+            (CloseCode::Away, 1001),
+            (CloseCode::Protocol, 1002),
+            (CloseCode::other(4321).unwrap(), 4321),
+        ];
+        for (code, number) in codes {
+            // what the browser is handed on wasm too
+            assert_eq!(u16::from(code), number);
+            let (url, server) = close_frame_server(false);
+            let mut app = app(Duration::from_millis(10));
+            app.world_mut()
+                .send_event(WebSocketConnectionEvents::SetupConnection(Some(url)));
+            let connection = update_until(&mut app, |app| drain::<ConnectionOpened>(app).pop());
+            app.world_mut().send_event(Disconnect {
+                entity: connection.entity,
+                code,
+                reason: "see you".to_owned(),
+            });
+            let closed = update_until(&mut app, |app| drain::<ConnectionClosed>(app).pop());
+            assert_eq!(closed.entity, connection.entity);
+            let frame = server
+                .join()
+                .unwrap()
+                .expect("closed without a close frame");
+            assert_eq!(u16::from(frame.code), number);
+            assert_eq!(frame.reason, "see you");
+        }
+    }
+
+    #[test]
+    fn closing_gives_up_on_servers_that_never_answer() {
+        let (url, server) = close_frame_server(true);
+        let mut app = app(Duration::from_millis(10));
+        app.insert_resource(CloseTimeout(Duration::from_millis(200)));
+        app.world_mut()
+            .send_event(WebSocketConnectionEvents::SetupConnection(Some(url)));
+        let connection = update_until(&mut app, |app| drain::<ConnectionOpened>(app).pop()).entity;
+        app.world_mut().send_event(Disconnect {
+            entity: connection,
+            code: CloseCode::Normal,
+            reason: "bye".to_owned(),
+        });
+        app.update();
+        let since = app.world().resource::<Time>().elapsed();
+        let closed = update_until(&mut app, |app| drain::<ConnectionClosed>(app).pop());
+        assert_eq!(closed.reason, "close timeout");
+        let waited = app.world().resource::<Time>().elapsed() - since;
+        assert!(waited >= Duration::from_millis(190), "{waited:?}");
+        let state = app.world().get::<ConnectionState>(connection);
+        assert_eq!(state, Some(&ConnectionState::Disconnected));
+        assert!(app.world().get::<WebSocketClient>(connection).is_none());
+        // our socket is gone, so the server sees us hang up
+        assert!(server.join().unwrap().is_some());
+    }
+
+    #[test]
+    fn stalled_connections_are_probed_then_dropped() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}/", listener.local_addr().unwrap());
+        // completes the handshake, then neither reads nor answers, like a half-dead peer
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let _socket = tungstenite::accept(stream.try_clone().unwrap()).unwrap();
+            std::iter::from_fn(|| read_raw_frame(&mut stream))
+                .map(|(_, opcode, _)| opcode)
+                .collect::<Vec<_>>()
+        });
+        let mut app = app(Duration::from_millis(50));
+        app.insert_resource(StallTimeout {
+            probe_after: Duration::from_millis(300),
+            dead_after: Duration::from_millis(600),
+        });
+        app.world_mut()
+            .send_event(WebSocketConnectionEvents::SetupConnection(Some(url)));
+        update_until(&mut app, |app| drain::<ConnectionOpened>(app).pop());
+        let opened = app.world().resource::<Time>().elapsed();
+        let closed = update_until(&mut app, |app| drain::<ConnectionClosed>(app).pop());
+        assert_eq!(closed.reason, "stalled");
+        let stalled = app.world().resource::<Time>().elapsed() - opened;
+        assert!(stalled >= Duration::from_millis(600), "{stalled:?}");
+        // a ping to provoke an answer, then the close frame
+        assert_eq!(server.join().unwrap(), [0x9, 0x8]);
+    }
+
+    #[test]
+    fn strict_protocol_closes_on_violations() {
+        let violations = [
+            Message::Text("hello".to_owned()),
+            Message::Pong(ping_payload(7, b"nobody asked")),
+        ];
+        for violation in violations {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let url = format!("ws://{}/", listener.local_addr().unwrap());
+            let server = std::thread::spawn(move || {
+                let (stream, _) = listener.accept().unwrap();
+                let mut socket = tungstenite::accept(stream).unwrap();
+                socket.send(violation).unwrap();
+                loop {
+                    match socket.read() {
+                        Ok(Message::Close(frame)) => {
+                            let _ = socket.flush();
+                            return frame.map(|frame| frame.into_owned());
+                        }
+                        Ok(_) => {}
+                        Err(_) => return None,
+                    }
+                }
+            });
+            let mut app = app(Duration::from_millis(10));
+            app.insert_resource(StrictProtocol(true));
+            app.world_mut()
+                .send_event(WebSocketConnectionEvents::SetupConnection(Some(url)));
+            update_until(&mut app, |app| drain::<ConnectionClosed>(app).pop());
+            assert!(drain::<WebSocketMessageReceived>(&mut app).is_empty());
+            let frame = server
+                .join()
+                .unwrap()
+                .expect("closed without a close frame");
+            assert_eq!(u16::from(frame.code), 1002);
+        }
+    }
+
+    #[test]
+    fn exiting_sends_what_is_queued_and_a_close_frame() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}/", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut socket = tungstenite::accept(stream).unwrap();
+            let mut received = Vec::new();
+            loop {
+                match socket.read() {
+                    Ok(Message::Binary(data)) => received.push(data),
+                    Ok(Message::Close(frame)) => {
+                        return (received, frame.map(|frame| frame.into_owned()));
+                    }
+                    Ok(_) => {}
+                    Err(_) => return (received, None),
+                }
+            }
+        });
+        let mut app = app(Duration::from_millis(10));
+        app.world_mut()
+            .send_event(WebSocketConnectionEvents::SetupConnection(Some(url)));
+        let connection = update_until(&mut app, |app| drain::<ConnectionOpened>(app).pop()).entity;
+        app.world_mut().send_event(SendTo {
+            entity: connection,
+            data: b"last words".to_vec(),
+        });
+        app.world_mut().send_event(AppExit::Success);
+        app.update();
+        drop(app);
+        let (received, frame) = server.join().unwrap();
+        assert_eq!(received, [b"last words".to_vec()]);
+        let frame = frame.expect("closed without a close frame");
+        assert_eq!(u16::from(frame.code), 1001);
+        assert_eq!(frame.reason, "shutting down");
+    }
+}
@@ -0,0 +1,296 @@
+//! Shrinking messages on the wire with [`Compression`] and [`BinaryDiff`].
+
+use std::io::{Read, Write};
+
+use bevy::prelude::*;
+use flate2::{read::DeflateDecoder, write::DeflateEncoder};
+
+/// Opt-in binary diffing for a connection: every message is sent as a deflated bsdiff patch
+/// against the previous one, or whole when the patch wouldn't be smaller.
+///
+/// This is meant for large, slowly changing payloads. Both peers need to use it.
+#[derive(Component, Default)]
+pub struct BinaryDiff {
+    pub(crate) last_sent: Option<Vec<u8>>,
+    last_received: Option<Vec<u8>>,
+}
+
+const DIFF_FULL: u8 = 0;
+const DIFF_PATCH: u8 = 1;
+/// Received patches that inflate to more than this are dropped, like
+/// [`Compression::max_decompressed_size`]
+const MAX_PATCH_SIZE: u64 = 16 << 20;
+
+impl BinaryDiff {
+    pub(crate) fn encode(&mut self, msg: Vec<u8>) -> Vec<u8> {
+        if let Some(last) = &self.last_sent {
+            // the raw patch is about as long as the message, mostly zeros for unchanged bytes
+            let mut patch = Vec::new();
+            let frame = bsdiff::diff(last, &msg, &mut patch).and_then(|_| {
+                let mut encoder =
+                    DeflateEncoder::new(vec![DIFF_PATCH], flate2::Compression::fast());
+                encoder.write_all(&patch)?;
+                encoder.finish()
+            });
+            if let Some(frame) = frame.ok().filter(|frame| frame.len() <= msg.len()) {
+                self.last_sent = Some(msg);
+                return frame;
+            }
+        }
+        let mut frame = Vec::with_capacity(msg.len() + 1);
+        frame.push(DIFF_FULL);
+        frame.extend_from_slice(&msg);
+        self.last_sent = Some(msg);
+        frame
+    }
+
+    /// Reconstruct a message from a frame made by [`BinaryDiff::encode`]
+    pub(crate) fn decode(&mut self, frame: &[u8]) -> Option<Vec<u8>> {
+        let (tag, body) = frame.split_first()?;
+        let msg = match *tag {
+            DIFF_FULL => body.to_vec(),
+            DIFF_PATCH => {
+                let mut patch = DeflateDecoder::new(body).take(MAX_PATCH_SIZE);
+                let mut msg = Vec::new();
+                bsdiff::patch(self.last_received.as_ref()?, &mut patch, &mut msg).ok()?;
+                msg
+            }
+            _ => return None,
+        };
+        self.last_received = Some(msg.clone());
+        Some(msg)
+    }
+}
+
+/// Opt-in deflate compression for a connection's messages.
+///
+/// Only messages larger than `compress_threshold` bytes are compressed, since small ones tend to
+/// grow instead and aren't worth the CPU. Every frame is tagged with whether it's compressed,
+/// so both peers need this component, but they don't need to agree on the threshold.
+#[derive(Component, Clone)]
+pub struct Compression {
+    pub compress_threshold: usize,
+    pub dictionary: Option<CompressionDictionary>,
+    /// Drop received messages that inflate to more than this, so a small frame can't
+    /// decompress into gigabytes
+    pub max_decompressed_size: usize,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self {
+            compress_threshold: 1024,
+            dictionary: None,
+            max_decompressed_size: 1 << 20,
+        }
+    }
+}
+
+/// Preset deflate dictionary priming [`Compression`], e.g. a typical message of the protocol.
+/// Small messages with a repetitive structure compress much better with one, so consider
+/// lowering `compress_threshold` along with it.
+///
+/// Both peers must use the exact same dictionary. A peer without one rejects the messages,
+/// but a different dictionary decodes to garbage or fails, there's no way to tell them apart.
+#[derive(Clone)]
+pub struct CompressionDictionary(pub Vec<u8>);
+
+impl CompressionDictionary {
+    fn deflate(&self, msg: &[u8], out: Vec<u8>) -> Option<Vec<u8>> {
+        let mut compress = flate2::Compress::new(flate2::Compression::fast(), false);
+        compress.set_dictionary(&self.0).ok()?;
+        let mut out = out;
+        out.reserve(msg.len() / 2 + 64);
+        loop {
+            let consumed = compress.total_in() as usize;
+            let status = compress
+                .compress_vec(&msg[consumed..], &mut out, flate2::FlushCompress::Finish)
+                .ok()?;
+            match status {
+                flate2::Status::StreamEnd => return Some(out),
+                _ => out.reserve(out.capacity()),
+            }
+        }
+    }
+
+    /// Inflate `body`, `None` if it's broken or inflates to more than `limit` bytes
+    fn inflate(&self, body: &[u8], limit: usize) -> Option<Vec<u8>> {
+        let mut decompress = flate2::Decompress::new(false);
+        decompress.set_dictionary(&self.0).ok()?;
+        // one byte more than allowed, so going over shows as a full buffer
+        let mut msg = Vec::with_capacity((body.len() * 2 + 64).min(limit + 1));
+        loop {
+            let consumed = decompress.total_in() as usize;
+            let status = decompress
+                .decompress_vec(&body[consumed..], &mut msg, flate2::FlushDecompress::Finish)
+                .ok()?;
+            match status {
+                flate2::Status::StreamEnd if msg.len() <= limit => return Some(msg),
+                _ if msg.len() > limit => return None,
+                // no progress with room to spare means the input ended early
+                _ if msg.len() < msg.capacity() => return None,
+                _ => msg.reserve_exact(msg.capacity().min(limit + 1 - msg.len())),
+            }
+        }
+    }
+}
+
+const UNCOMPRESSED: u8 = 0;
+const DEFLATED: u8 = 1;
+/// Deflated with the [`CompressionDictionary`]
+const DEFLATED_WITH_DICTIONARY: u8 = 2;
+
+impl Compression {
+    pub(crate) fn encode(&self, msg: Vec<u8>) -> Vec<u8> {
+        if msg.len() > self.compress_threshold {
+            // random or already compressed data grows, then it's cheaper to send it as is
+            let smaller = |frame: &Vec<u8>| frame.len() <= msg.len();
+            if let Some(dictionary) = &self.dictionary {
+                let frame = dictionary.deflate(&msg, vec![DEFLATED_WITH_DICTIONARY]);
+                if let Some(frame) = frame.filter(smaller) {
+                    return frame;
+                }
+            }
+            let mut encoder = DeflateEncoder::new(vec![DEFLATED], flate2::Compression::fast());
+            let frame = encoder.write_all(&msg).and_then(|_| encoder.finish());
+            if let Some(frame) = frame.ok().filter(smaller) {
+                return frame;
+            }
+        }
+        let mut frame = Vec::with_capacity(msg.len() + 1);
+        frame.push(UNCOMPRESSED);
+        frame.extend_from_slice(&msg);
+        frame
+    }
+
+    /// Reconstruct a message from a frame made by [`Compression::encode`], `None` if it's
+    /// broken or inflates to more than [`Compression::max_decompressed_size`]
+    pub(crate) fn decode(&self, frame: &[u8]) -> Option<Vec<u8>> {
+        let limit = self.max_decompressed_size;
+        let (tag, body) = frame.split_first()?;
+        match *tag {
+            UNCOMPRESSED => Some(body.to_vec()),
+            DEFLATED => {
+                let mut msg = Vec::new();
+                DeflateDecoder::new(body)
+                    .take(limit as u64 + 1)
+                    .read_to_end(&mut msg)
+                    .ok()?;
+                (msg.len() <= limit).then_some(msg)
+            }
+            DEFLATED_WITH_DICTIONARY => self.dictionary.as_ref()?.inflate(body, limit),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::noise;
+
+    #[test]
+    fn compression_round_trips() {
+        let compression = Compression::default();
+        let msg = vec![b'a'; 4096];
+        let frame = compression.encode(msg.clone());
+        assert_eq!(frame[0], DEFLATED);
+        assert!(frame.len() < msg.len());
+        assert_eq!(compression.decode(&frame), Some(msg));
+    }
+
+    #[test]
+    fn compression_leaves_small_messages_alone() {
+        let compression = Compression::default();
+        let msg = vec![b'a'; compression.compress_threshold];
+        let frame = compression.encode(msg.clone());
+        assert_eq!(frame[0], UNCOMPRESSED);
+        assert_eq!(compression.decode(&frame), Some(msg));
+    }
+
+    #[test]
+    fn compression_sends_incompressible_messages_as_is() {
+        let compression = Compression {
+            dictionary: Some(CompressionDictionary(b"aaaa".to_vec())),
+            ..default()
+        };
+        let msg = noise(4096);
+        let frame = compression.encode(msg.clone());
+        assert_eq!(frame[0], UNCOMPRESSED);
+        assert_eq!(frame.len(), msg.len() + 1);
+        assert_eq!(compression.decode(&frame), Some(msg));
+    }
+
+    #[test]
+    fn decompression_stops_at_the_size_limit() {
+        let msg = vec![0; 64 * 1024];
+        for dictionary in [None, Some(CompressionDictionary(vec![0; 32]))] {
+            let sender = Compression {
+                dictionary: dictionary.clone(),
+                ..default()
+            };
+            let frame = sender.encode(msg.clone());
+            assert_ne!(frame[0], UNCOMPRESSED);
+            let at_limit = Compression {
+                dictionary: dictionary.clone(),
+                max_decompressed_size: msg.len(),
+                ..default()
+            };
+            assert_eq!(at_limit.decode(&frame), Some(msg.clone()));
+            let below_limit = Compression {
+                dictionary,
+                max_decompressed_size: msg.len() - 1,
+                ..default()
+            };
+            assert_eq!(below_limit.decode(&frame), None);
+        }
+    }
+
+    #[test]
+    fn dictionaries_shrink_small_repetitive_frames() {
+        let dictionary = CompressionDictionary(
+            br#"{"id":,"translation":[,,],"rotation":[,,,],"scale":[1.0,1.0,1.0]}"#.to_vec(),
+        );
+        let plain = Compression {
+            compress_threshold: 0,
+            ..default()
+        };
+        let primed = Compression {
+            dictionary: Some(dictionary),
+            ..plain.clone()
+        };
+        let (mut without, mut with) = (0, 0);
+        for id in 0..20 {
+            let msg = format!(
+                r#"{{"id":{id},"translation":[{id}.5,0.0,-{id}.25],"rotation":[0.0,0.0,0.0,1.0],"scale":[1.0,1.0,1.0]}}"#
+            )
+            .into_bytes();
+            let frame = primed.encode(msg.clone());
+            assert_eq!(primed.decode(&frame), Some(msg.clone()));
+            // a peer without the dictionary can't make sense of the frame
+            assert_ne!(plain.decode(&frame), Some(msg.clone()));
+            with += frame.len();
+            without += plain.encode(msg).len();
+        }
+        assert!(with * 2 < without, "{with} bytes with, {without} without");
+    }
+
+    #[test]
+    fn binary_diffs_reconstruct_successive_messages() {
+        let (mut sender, mut receiver) = (BinaryDiff::default(), BinaryDiff::default());
+        let mut msg = noise(4096);
+        for version in 0..10u8 {
+            // a few edits and a growing tail
+            msg[usize::from(version) * 100] = version;
+            msg.extend_from_slice(&[version; 16]);
+            let frame = sender.encode(msg.clone());
+            let tag = if version == 0 { DIFF_FULL } else { DIFF_PATCH };
+            assert_eq!(frame[0], tag);
+            assert_eq!(receiver.decode(&frame), Some(msg.clone()));
+        }
+        // a patch only applies to the message it was made against
+        let patch = sender.encode(noise(4096));
+        assert_eq!(patch[0], DIFF_PATCH);
+        assert_eq!(BinaryDiff::default().decode(&patch), None);
+    }
+}
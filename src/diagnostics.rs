@@ -0,0 +1,252 @@
+//! Bandwidth and connection diagnostics, telemetry, and [`ConnectionSnapshots`] for debug UIs.
+
+use std::time::Duration;
+
+use bevy::{
+    diagnostic::{Diagnostic, DiagnosticPath, Diagnostics, RegisterDiagnostic},
+    prelude::*,
+};
+
+use crate::{
+    Bandwidth, ConnectionState, IsSecure, Outbox, ReconnectAttempts, ReconnectTimer, Rtt,
+    ServerUrl, WebSocketClient,
+};
+
+#[cfg(feature = "telemetry")]
+use crate::{
+    ConnectionClosed, ConnectionFailed, ConnectionOpened, MessageSendOutcome, SendOutcome,
+    WebSocketMessageBatch, WebSocketMessageReceived,
+};
+
+/// Reports bandwidth and connection counts to the [`DiagnosticsStore`](bevy::diagnostic::DiagnosticsStore).
+///
+/// Registers its own diagnostics, so it needs neither the perf UI nor any other
+/// diagnostics plugin.
+pub struct NetworkDiagnosticsPlugin;
+
+impl NetworkDiagnosticsPlugin {
+    const BYTES_SENT: DiagnosticPath = DiagnosticPath::const_new("network/bytes_sent_per_second");
+    const BYTES_RECEIVED: DiagnosticPath =
+        DiagnosticPath::const_new("network/bytes_received_per_second");
+    const MESSAGES: DiagnosticPath = DiagnosticPath::const_new("network/messages_per_second");
+    const CONNECTIONS: DiagnosticPath = DiagnosticPath::const_new("network/connections");
+    const QUEUED_BYTES: DiagnosticPath = DiagnosticPath::const_new("network/queued_bytes");
+}
+
+impl Plugin for NetworkDiagnosticsPlugin {
+    fn build(&self, app: &mut App) {
+        app.register_diagnostic(Diagnostic::new(Self::BYTES_SENT).with_suffix(" B/s"))
+            .register_diagnostic(Diagnostic::new(Self::BYTES_RECEIVED).with_suffix(" B/s"))
+            .register_diagnostic(Diagnostic::new(Self::MESSAGES).with_suffix(" msg/s"))
+            .register_diagnostic(Diagnostic::new(Self::CONNECTIONS))
+            .register_diagnostic(Diagnostic::new(Self::QUEUED_BYTES).with_suffix(" B"))
+            .init_resource::<Bandwidth>()
+            .add_systems(Update, (add_network_stats, update_network_diagnostics));
+    }
+}
+
+/// Totals for one connection since its entity was set up, reconnects included, e.g. for a
+/// per-connection bandwidth readout. The rates in the diagnostics are across all connections.
+#[derive(Component, Default)]
+pub struct NetworkStats {
+    pub(crate) bytes_sent: u64,
+    pub(crate) bytes_received: u64,
+    pub(crate) messages_sent: u64,
+    pub(crate) messages_received: u64,
+}
+
+pub fn add_network_stats(
+    mut commands: Commands,
+    connections: Query<Entity, (With<WebSocketClient>, Without<NetworkStats>)>,
+) {
+    for entity in &connections {
+        commands.entity(entity).insert(NetworkStats::default());
+    }
+}
+
+pub fn update_network_diagnostics(
+    mut diagnostics: Diagnostics,
+    bandwidth: Res<Bandwidth>,
+    connections: Query<(), With<WebSocketClient>>,
+    outboxes: Query<&Outbox>,
+) {
+    diagnostics.add_measurement(&NetworkDiagnosticsPlugin::BYTES_SENT, || {
+        bandwidth.sent_per_second as f64
+    });
+    diagnostics.add_measurement(&NetworkDiagnosticsPlugin::BYTES_RECEIVED, || {
+        bandwidth.received_per_second as f64
+    });
+    diagnostics.add_measurement(&NetworkDiagnosticsPlugin::MESSAGES, || {
+        bandwidth.messages_per_second as f64
+    });
+    diagnostics.add_measurement(&NetworkDiagnosticsPlugin::CONNECTIONS, || {
+        connections.iter().count() as f64
+    });
+    diagnostics.add_measurement(&NetworkDiagnosticsPlugin::QUEUED_BYTES, || {
+        outboxes.iter().map(Outbox::pending_bytes).sum::<usize>() as f64
+    });
+}
+
+/// Emit connection and message metrics as `tracing` events with the target
+/// `bevy_websocket::metrics`, for production telemetry. The fields follow the
+/// `tracing-opentelemetry` metrics convention, so its `MetricsLayer` turns them into
+/// OpenTelemetry instruments:
+///
+/// - `websocket.messages_sent`, `websocket.bytes_sent`: counters, per connection
+/// - `websocket.send_failures`: counter of dropped messages, with the error
+/// - `websocket.messages_received`, `websocket.bytes_received`: counters, per connection
+/// - `websocket.connections_opened`, `websocket.connections_closed`: counters, closes with
+///   the reason
+/// - `websocket.connection_failures`: counter, with the error and attempt
+/// - `websocket.connect_failure_duration`: histogram of how long failed attempts took, in seconds
+///
+/// Every event carries the connection entity as `connection`.
+#[cfg(feature = "telemetry")]
+pub fn emit_telemetry(
+    mut ev_outcome: EventReader<MessageSendOutcome>,
+    mut ev_received: EventReader<WebSocketMessageReceived>,
+    mut ev_batch: EventReader<WebSocketMessageBatch>,
+    mut ev_opened: EventReader<ConnectionOpened>,
+    mut ev_failed: EventReader<ConnectionFailed>,
+    mut ev_closed: EventReader<ConnectionClosed>,
+) {
+    const TARGET: &str = "bevy_websocket::metrics";
+    for MessageSendOutcome {
+        entity,
+        bytes,
+        outcome,
+    } in ev_outcome.read()
+    {
+        match outcome {
+            SendOutcome::Sent | SendOutcome::Queued => info!(
+                target: TARGET,
+                connection = %entity,
+                monotonic_counter.websocket.messages_sent = 1_u64,
+                monotonic_counter.websocket.bytes_sent = *bytes as u64,
+            ),
+            SendOutcome::WouldBlock | SendOutcome::DryRun => {}
+            SendOutcome::Failed(error) => info!(
+                target: TARGET,
+                connection = %entity,
+                error = %error,
+                monotonic_counter.websocket.send_failures = 1_u64,
+            ),
+        }
+    }
+    let received = ev_received
+        .read()
+        .map(|ev| (ev.entity, ev.data.len()))
+        .chain(ev_batch.read().flat_map(|ev| {
+            ev.messages
+                .iter()
+                .map(|message| (ev.entity, message.data.len()))
+        }));
+    for (entity, bytes) in received {
+        info!(
+            target: TARGET,
+            connection = %entity,
+            monotonic_counter.websocket.messages_received = 1_u64,
+            monotonic_counter.websocket.bytes_received = bytes as u64,
+        );
+    }
+    for ConnectionOpened { entity, .. } in ev_opened.read() {
+        info!(
+            target: TARGET,
+            connection = %entity,
+            monotonic_counter.websocket.connections_opened = 1_u64,
+        );
+    }
+    for ev in ev_failed.read() {
+        info!(
+            target: TARGET,
+            connection = %ev.entity,
+            error = %ev.error,
+            attempt = ev.attempt,
+            monotonic_counter.websocket.connection_failures = 1_u64,
+            histogram.websocket.connect_failure_duration = ev.elapsed.as_secs_f64(),
+        );
+    }
+    for ConnectionClosed { entity, reason } in ev_closed.read() {
+        info!(
+            target: TARGET,
+            connection = %entity,
+            reason = %reason,
+            monotonic_counter.websocket.connections_closed = 1_u64,
+        );
+    }
+}
+
+/// Everything we know about one connection, gathered in one place for debug UIs
+#[derive(Clone, Debug)]
+pub struct ConnectionSnapshot {
+    pub entity: Entity,
+    /// The entity's [`Name`], or its URL if it has none
+    pub name: String,
+    pub state: ConnectionState,
+    /// Waiting on the [`ReconnectPolicy`](crate::ReconnectPolicy) backoff
+    pub reconnecting: bool,
+    pub rtt: Option<Duration>,
+    /// Messages and bytes waiting in the [`Outbox`]
+    pub queued: (usize, usize),
+    pub reconnect_attempts: u32,
+    pub secure: Option<bool>,
+}
+
+/// Snapshots of all connections, refreshed every frame while this resource exists.
+///
+/// Not inserted by default, so apps without a debug overlay don't pay for it.
+#[derive(Resource, Default)]
+pub struct ConnectionSnapshots(pub Vec<ConnectionSnapshot>);
+
+#[allow(clippy::type_complexity)]
+pub fn collect_connection_snapshots(
+    mut snapshots: ResMut<ConnectionSnapshots>,
+    connections: Query<(
+        Entity,
+        &ServerUrl,
+        Option<&Name>,
+        &ConnectionState,
+        Has<ReconnectTimer>,
+        Option<&Rtt>,
+        Option<&Outbox>,
+        Option<&ReconnectAttempts>,
+        Option<&IsSecure>,
+    )>,
+) {
+    snapshots.0.clear();
+    for (entity, url, name, state, reconnecting, rtt, outbox, attempts, secure) in &connections {
+        snapshots.0.push(ConnectionSnapshot {
+            entity,
+            name: name.map_or_else(|| url.0.clone(), |name| name.to_string()),
+            state: *state,
+            reconnecting,
+            rtt: rtt.map(|rtt| rtt.0),
+            queued: outbox.map_or((0, 0), |outbox| {
+                (outbox.pending_count(), outbox.pending_bytes())
+            }),
+            reconnect_attempts: attempts.map_or(0, |attempts| attempts.0),
+            secure: secure.map(|secure| secure.0),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::connected_app;
+
+    #[test]
+    fn network_diagnostics_work_without_other_diagnostics_plugins() {
+        // `app` only has `MinimalPlugins`, so no `DiagnosticsPlugin` or perf UI
+        let (mut app, _) = connected_app();
+        app.update();
+        let store = app.world().resource::<bevy::diagnostic::DiagnosticsStore>();
+        let connections = store
+            .get(&NetworkDiagnosticsPlugin::CONNECTIONS)
+            .and_then(Diagnostic::value);
+        assert_eq!(connections, Some(1.0));
+        assert!(store
+            .get(&NetworkDiagnosticsPlugin::QUEUED_BYTES)
+            .is_some_and(|queued| queued.value().is_some()));
+    }
+}
@@ -0,0 +1,134 @@
+//! Packs several messages into one websocket message, each prefixed with its length as a
+//! LEB128 varint, so small messages cost one or two bytes of framing instead of four.
+
+use thiserror::Error;
+
+/// Longest varint we accept, enough for any `u64`
+const MAX_VARINT_LEN: usize = 10;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum UnframeError {
+    #[error("length prefix is cut off")]
+    TruncatedLength,
+    #[error("length prefix is longer than {MAX_VARINT_LEN} bytes or overflows")]
+    InvalidLength,
+    #[error("message of {len} bytes is cut off after {available}")]
+    TruncatedMessage { len: usize, available: usize },
+}
+
+/// Append `msg` to `buf` with its length prefix. Empty messages are allowed.
+pub fn frame_into(buf: &mut Vec<u8>, msg: &[u8]) {
+    let mut len = msg.len() as u64;
+    loop {
+        let byte = (len & 0x7f) as u8;
+        len >>= 7;
+        if len == 0 {
+            buf.push(byte);
+            break;
+        }
+        buf.push(byte | 0x80);
+    }
+    buf.extend_from_slice(msg);
+}
+
+/// Pack `messages` into one buffer
+pub fn frame<'a>(messages: impl IntoIterator<Item = &'a [u8]>) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for msg in messages {
+        frame_into(&mut buf, msg);
+    }
+    buf
+}
+
+/// Split a buffer made by [`frame`] back into its messages
+pub fn unframe(mut data: &[u8]) -> Result<Vec<&[u8]>, UnframeError> {
+    let mut messages = Vec::new();
+    while !data.is_empty() {
+        let (len, rest) = read_varint(data)?;
+        let len = usize::try_from(len).map_err(|_| UnframeError::InvalidLength)?;
+        if len > rest.len() {
+            return Err(UnframeError::TruncatedMessage {
+                len,
+                available: rest.len(),
+            });
+        }
+        let (msg, rest) = rest.split_at(len);
+        messages.push(msg);
+        data = rest;
+    }
+    Ok(messages)
+}
+
+fn read_varint(data: &[u8]) -> Result<(u64, &[u8]), UnframeError> {
+    let mut value = 0u64;
+    for (i, &byte) in data.iter().enumerate().take(MAX_VARINT_LEN) {
+        let bits = u64::from(byte & 0x7f);
+        // the 10th byte may only carry the top bit of a u64
+        if i == MAX_VARINT_LEN - 1 && bits > 1 {
+            return Err(UnframeError::InvalidLength);
+        }
+        value |= bits << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok((value, &data[i + 1..]));
+        }
+    }
+    if data.len() < MAX_VARINT_LEN {
+        Err(UnframeError::TruncatedLength)
+    } else {
+        Err(UnframeError::InvalidLength)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_boundary_lengths() {
+        for len in [0, 1, 127, 128, 16383, 16384] {
+            let msg = vec![0xab; len];
+            let framed = frame([&msg[..]]);
+            let prefix = framed.len() - len;
+            assert_eq!(
+                prefix,
+                if len < 128 {
+                    1
+                } else if len < 16384 {
+                    2
+                } else {
+                    3
+                }
+            );
+            assert_eq!(unframe(&framed), Ok(vec![&msg[..]]), "length {len}");
+        }
+    }
+
+    #[test]
+    fn round_trips_several_messages() {
+        let messages: [&[u8]; 4] = [b"a", b"", &[7; 128], b"last"];
+        assert_eq!(unframe(&frame(messages)), Ok(messages.to_vec()));
+        assert_eq!(unframe(&[]), Ok(vec![]));
+    }
+
+    #[test]
+    fn rejects_truncated_input() {
+        let framed = frame([&[1; 200][..]]);
+        assert_eq!(unframe(&framed[..1]), Err(UnframeError::TruncatedLength));
+        assert_eq!(
+            unframe(&framed[..framed.len() - 1]),
+            Err(UnframeError::TruncatedMessage {
+                len: 200,
+                available: 199
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_overlong_lengths() {
+        assert_eq!(unframe(&[0xff; 11]), Err(UnframeError::InvalidLength));
+        // 10 bytes, but more than 64 bits
+        let mut overflow = vec![0xff; 9];
+        overflow.push(0x02);
+        assert_eq!(unframe(&overflow), Err(UnframeError::InvalidLength));
+    }
+}
@@ -0,0 +1,436 @@
+//! Pinging peers with [`Heartbeat`]s to keep connections alive and measure their [`Rtt`].
+
+use std::{
+    collections::VecDeque,
+    hash::{Hash, Hasher},
+    time::Duration,
+};
+
+use bevy::prelude::*;
+
+use crate::{
+    CloseCode, Closing, Disconnect, Outbox, OutboxMemoryBudget, StrictProtocol, WebSocketClient,
+    STALL_PROBE_ID,
+};
+
+#[cfg(not(target_arch = "wasm32"))]
+use std::io::ErrorKind;
+#[cfg(not(target_arch = "wasm32"))]
+use tungstenite::Message;
+
+/// How [`Heartbeat`] checks that a connection is still alive
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HeartbeatMode {
+    /// WebSocket ping/pong control frames, native only
+    Protocol,
+    /// Small tagged data messages the peer answers, for browsers and for proxies that strip pings
+    Application,
+    /// Protocol pings where they're available, application pings on wasm
+    Auto,
+}
+
+/// Periodically pings the peer to keep the connection warm and measure its [`Rtt`]
+#[derive(Component)]
+pub struct Heartbeat {
+    pub(crate) mode: HeartbeatMode,
+    /// Time between pings before [`HeartbeatJitter`]
+    pub(crate) interval: Duration,
+    timer: Timer,
+    next_id: u32,
+    /// Sent along with every ping id, e.g. a connection id, so pongs in logs and captures can be
+    /// told apart. Pongs that don't echo it don't count as an answer.
+    pub(crate) tag: Vec<u8>,
+    /// Ids and [`Time::elapsed`] of the pings we're waiting on, oldest first
+    outstanding: VecDeque<(u32, Duration)>,
+    /// [`Time::elapsed`] when the peer last answered a ping
+    last_pong: Option<Duration>,
+}
+
+/// Pings still waiting on a pong beyond this, or for this many intervals, are given up on
+const MAX_OUTSTANDING_PINGS: usize = 8;
+
+impl Heartbeat {
+    pub fn new(mode: HeartbeatMode, interval: Duration) -> Self {
+        Self {
+            mode,
+            interval,
+            timer: Timer::new(interval, TimerMode::Repeating),
+            next_id: 0,
+            tag: Vec::new(),
+            outstanding: VecDeque::new(),
+            last_pong: None,
+        }
+    }
+
+    pub fn with_tag(mut self, tag: impl Into<Vec<u8>>) -> Self {
+        self.tag = tag.into();
+        self
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn uses_protocol_pings(&self) -> bool {
+        // browsers don't let us send ping frames
+        self.mode != HeartbeatMode::Application && cfg!(not(target_arch = "wasm32"))
+    }
+}
+
+/// Spread every [`Heartbeat`]'s pings by up to `fraction` of its interval either way, at most
+/// half, so connections set up together don't keep pinging the server in the same frame. The offsets
+/// are hashed from `seed`, the connection and the ping id, so the same seed gives the same
+/// schedule. Off by default.
+#[derive(Resource, Default)]
+pub struct HeartbeatJitter {
+    pub fraction: f32,
+    pub seed: u64,
+}
+
+impl HeartbeatJitter {
+    /// How long `entity` waits before sending ping `id`
+    fn interval(&self, base: Duration, entity: Entity, id: u32) -> Duration {
+        if self.fraction <= 0.0 {
+            return base;
+        }
+        let mut hasher = std::hash::DefaultHasher::new();
+        (self.seed, entity, id).hash(&mut hasher);
+        // the top 53 bits as a float in -1..1
+        let offset = (hasher.finish() >> 11) as f64 / (1u64 << 52) as f64 - 1.0;
+        base.mul_f64(1.0 + f64::from(self.fraction.min(0.5)) * offset)
+    }
+}
+
+/// Round trip time measured by the last answered [`Heartbeat`] ping
+#[derive(Component)]
+pub struct Rtt(pub Duration);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HeartbeatKind {
+    Ping,
+    Pong,
+}
+
+/// Application heartbeats are tagged like this, followed by the ping payload
+pub const HEARTBEAT_PING: [u8; 4] = [0xff, b'h', b'b', b'?'];
+pub const HEARTBEAT_PONG: [u8; 4] = [0xff, b'h', b'b', b'!'];
+
+/// What a ping carries and its pong echoes: the big endian ping id, then the [`Heartbeat`] tag
+pub(crate) fn ping_payload(id: u32, tag: &[u8]) -> Vec<u8> {
+    [&id.to_be_bytes()[..], tag].concat()
+}
+
+pub(crate) fn parse_ping_payload(payload: &[u8]) -> Option<(u32, &[u8])> {
+    let (id, tag) = payload.split_first_chunk::<4>()?;
+    Some((u32::from_be_bytes(*id), tag))
+}
+
+pub(crate) fn heartbeat_frame(kind: HeartbeatKind, payload: &[u8]) -> Vec<u8> {
+    let tag = match kind {
+        HeartbeatKind::Ping => HEARTBEAT_PING,
+        HeartbeatKind::Pong => HEARTBEAT_PONG,
+    };
+    [&tag[..], payload].concat()
+}
+
+pub(crate) fn parse_heartbeat(frame: &[u8]) -> Option<(HeartbeatKind, &[u8])> {
+    let (tag, payload) = frame.split_first_chunk::<4>()?;
+    let kind = match *tag {
+        HEARTBEAT_PING => HeartbeatKind::Ping,
+        HEARTBEAT_PONG => HeartbeatKind::Pong,
+        _ => return None,
+    };
+    // anything shorter can't be one of ours
+    parse_ping_payload(payload)?;
+    Some((kind, payload))
+}
+
+#[derive(Event)]
+pub struct HeartbeatReceived {
+    pub entity: Entity,
+    pub kind: HeartbeatKind,
+    /// The big endian ping id, then the [`Heartbeat`] tag
+    pub payload: Vec<u8>,
+}
+
+#[cfg_attr(target_arch = "wasm32", allow(unused_variables, unused_mut))]
+pub fn send_heartbeats(
+    mut q: Query<(Entity, &mut Heartbeat, &WebSocketClient, &mut Outbox), Without<Closing>>,
+    time: Res<Time>,
+    mut budget: ResMut<OutboxMemoryBudget>,
+    jitter: Res<HeartbeatJitter>,
+) {
+    for (entity, mut heartbeat, client, mut outbox) in q.iter_mut() {
+        if heartbeat.is_added() {
+            let first = jitter.interval(heartbeat.interval, entity, heartbeat.next_id);
+            heartbeat.timer.set_duration(first);
+        }
+        if !heartbeat.timer.tick(time.delta()).just_finished() {
+            continue;
+        }
+        if let Some((last, _)) = heartbeat.outstanding.back() {
+            warn!("{entity} didn't answer heartbeat {last} in time");
+        }
+        let id = heartbeat.next_id;
+        heartbeat.next_id = id.wrapping_add(1);
+        let now = time.elapsed();
+        let expiry = heartbeat.interval * MAX_OUTSTANDING_PINGS as u32;
+        heartbeat
+            .outstanding
+            .retain(|(_, sent)| now.saturating_sub(*sent) < expiry);
+        if heartbeat.outstanding.len() >= MAX_OUTSTANDING_PINGS {
+            heartbeat.outstanding.pop_front();
+        }
+        heartbeat.outstanding.push_back((id, now));
+        let next = jitter.interval(heartbeat.interval, entity, heartbeat.next_id);
+        heartbeat.timer.set_duration(next);
+        let payload = ping_payload(id, &heartbeat.tag);
+        #[cfg(not(target_arch = "wasm32"))]
+        if heartbeat.uses_protocol_pings() {
+            match client.socket().write(Message::Ping(payload)) {
+                Ok(_) => {}
+                Err(tungstenite::Error::Io(e)) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => warn!("Could not send a ping: {e:?}"),
+            }
+            continue;
+        }
+        if outbox
+            .push(heartbeat_frame(HeartbeatKind::Ping, &payload), &mut budget)
+            .is_err()
+        {
+            warn!("No room in the outbox for a heartbeat to {entity}");
+        }
+    }
+}
+
+pub fn handle_heartbeats(
+    mut commands: Commands,
+    mut ev_heartbeat: EventReader<HeartbeatReceived>,
+    mut q: Query<(Option<&mut Heartbeat>, &mut Outbox)>,
+    time: Res<Time>,
+    mut budget: ResMut<OutboxMemoryBudget>,
+    strict: Res<StrictProtocol>,
+    mut ev_disconnect: EventWriter<Disconnect>,
+) {
+    for HeartbeatReceived {
+        entity,
+        kind,
+        payload,
+    } in ev_heartbeat.read()
+    {
+        let Ok((heartbeat, mut outbox)) = q.get_mut(*entity) else {
+            continue;
+        };
+        match kind {
+            HeartbeatKind::Ping => {
+                if outbox
+                    .push(heartbeat_frame(HeartbeatKind::Pong, payload), &mut budget)
+                    .is_err()
+                {
+                    warn!("No room in the outbox to answer a heartbeat from {entity}");
+                }
+            }
+            HeartbeatKind::Pong => {
+                let Some((id, tag)) = parse_ping_payload(payload) else {
+                    continue;
+                };
+                let answered = heartbeat.and_then(|mut heartbeat| {
+                    if tag != heartbeat.tag {
+                        return None;
+                    }
+                    let index = heartbeat
+                        .outstanding
+                        .iter()
+                        .position(|(sent, _)| *sent == id)?;
+                    // the pings before this one may still be answered late, `send_heartbeats`
+                    // gives up on them once they're too old
+                    let (_, sent) = heartbeat.outstanding.remove(index)?;
+                    heartbeat.last_pong = Some(time.elapsed());
+                    Some(sent)
+                });
+                match answered {
+                    Some(sent) => {
+                        let rtt = time.elapsed().saturating_sub(sent);
+                        debug!("{entity} answered heartbeat {id} after {rtt:?}");
+                        commands.entity(*entity).insert(Rtt(rtt));
+                    }
+                    None if strict.0 && id != STALL_PROBE_ID => {
+                        ev_disconnect.send(Disconnect {
+                            entity: *entity,
+                            code: CloseCode::Protocol,
+                            reason: "unsolicited pong".to_owned(),
+                        });
+                    }
+                    None => {}
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, sync::mpsc};
+
+    use bevy::utils::HashMap;
+
+    use super::*;
+    use crate::{
+        testing::{app, connected_app, drain, update_until},
+        ConnectionOpened, WebSocketConnectionEvents,
+    };
+
+    #[test]
+    fn application_heartbeats_measure_the_round_trip() {
+        let (mut app, connection) = connected_app();
+        let heartbeat = Heartbeat::new(HeartbeatMode::Application, Duration::from_millis(50));
+        app.world_mut()
+            .entity_mut(connection)
+            .insert(heartbeat)
+            .remove::<Rtt>();
+        // the echo server reflects our ping, we answer it and get our own pong back
+        let rtt = update_until(&mut app, |app| {
+            app.world().get::<Rtt>(connection).map(|rtt| rtt.0)
+        });
+        assert!(
+            rtt > Duration::ZERO && rtt < Duration::from_secs(1),
+            "{rtt:?}"
+        );
+        let heartbeat = app.world().get::<Heartbeat>(connection).unwrap();
+        assert!(heartbeat.last_pong.is_some());
+    }
+
+    #[test]
+    fn unanswered_pings_are_given_up_on_once_they_are_too_old() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}/", listener.local_addr().unwrap());
+        // never answers
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut socket = tungstenite::accept(stream).unwrap();
+            while socket.read().is_ok() {}
+        });
+        let mut app = app(Duration::from_millis(10));
+        // long gaps would keep more than the last eight intervals' worth of pings around
+        app.insert_resource(HeartbeatJitter {
+            fraction: 0.5,
+            seed: 1,
+        });
+        app.world_mut()
+            .send_event(WebSocketConnectionEvents::SetupConnection(Some(url)));
+        let connection = update_until(&mut app, |app| drain::<ConnectionOpened>(app).pop()).entity;
+        let interval = Duration::from_millis(100);
+        app.world_mut()
+            .entity_mut(connection)
+            .insert(Heartbeat::new(HeartbeatMode::Application, interval));
+        let mut pings = 0;
+        for _ in 0..300 {
+            app.update();
+            let now = app.world().resource::<Time>().elapsed();
+            let heartbeat = app.world().get::<Heartbeat>(connection).unwrap();
+            if heartbeat.outstanding.back().map(|(_, sent)| *sent) != Some(now) {
+                continue;
+            }
+            pings += 1;
+            let oldest = heartbeat.outstanding.front().unwrap().1;
+            assert!(now - oldest < interval * MAX_OUTSTANDING_PINGS as u32);
+        }
+        assert!(pings > 20, "only {pings} pings");
+        drop(app);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn pongs_are_matched_to_their_ping_among_several() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}/", listener.local_addr().unwrap());
+        // lets three pings pile up, answers the middle one, then the first one late
+        let (late, answer_late) = mpsc::channel();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut socket = tungstenite::accept(stream).unwrap();
+            let mut pings = Vec::new();
+            while pings.len() < 3 {
+                if let Message::Binary(frame) = socket.read().unwrap() {
+                    if let Some((HeartbeatKind::Ping, payload)) = parse_heartbeat(&frame) {
+                        pings.push(parse_ping_payload(payload).unwrap().0);
+                    }
+                }
+            }
+            let pong = |id, tag: &[u8]| {
+                let frame = heartbeat_frame(HeartbeatKind::Pong, &ping_payload(id, tag));
+                Message::binary(frame)
+            };
+            // another connection's pong for the last ping doesn't count
+            socket.send(pong(pings[2], b"conn-8")).unwrap();
+            socket.send(pong(pings[1], b"conn-7")).unwrap();
+            answer_late.recv().unwrap();
+            socket.send(pong(pings[0], b"conn-7")).unwrap();
+            while socket.read().is_ok() {}
+        });
+        let mut app = app(Duration::from_millis(10));
+        app.world_mut()
+            .send_event(WebSocketConnectionEvents::SetupConnection(Some(url)));
+        let connection = update_until(&mut app, |app| drain::<ConnectionOpened>(app).pop()).entity;
+        // slow enough that the answered ping isn't given up on while the server catches up
+        let heartbeat = Heartbeat::new(HeartbeatMode::Application, Duration::from_millis(500))
+            .with_tag("conn-7");
+        app.world_mut()
+            .entity_mut(connection)
+            .insert(heartbeat)
+            .remove::<Rtt>();
+        let mut sent = HashMap::new();
+        let rtt = update_until(&mut app, |app| {
+            let world = app.world();
+            let heartbeat = world.get::<Heartbeat>(connection).unwrap();
+            sent.extend(heartbeat.outstanding.iter().copied());
+            world.get::<Rtt>(connection).map(|rtt| rtt.0)
+        });
+        let now = app.world().resource::<Time>().elapsed();
+        let heartbeat = app.world().get::<Heartbeat>(connection).unwrap();
+        let mut ids: Vec<_> = sent.keys().copied().collect();
+        ids.sort_unstable();
+        assert_eq!(rtt, now - sent[&ids[1]]);
+        // the others are still waiting
+        let waiting: Vec<_> = heartbeat.outstanding.iter().map(|(id, _)| *id).collect();
+        assert_eq!(waiting[..2], [ids[0], ids[2]]);
+
+        late.send(()).unwrap();
+        let rtt = update_until(&mut app, |app| {
+            let world = app.world();
+            let heartbeat = world.get::<Heartbeat>(connection).unwrap();
+            let answered = heartbeat.outstanding.iter().all(|(id, _)| *id != ids[0]);
+            answered.then(|| world.get::<Rtt>(connection).unwrap().0)
+        });
+        let now = app.world().resource::<Time>().elapsed();
+        assert_eq!(rtt, now - sent[&ids[0]]);
+        drop(app);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn jittered_pings_spread_across_connections() {
+        let mut world = World::new();
+        let connections: Vec<_> = (0..32).map(|_| world.spawn_empty().id()).collect();
+        let base = Duration::from_secs(10);
+        let schedule = |jitter: &HeartbeatJitter| -> Vec<_> {
+            connections
+                .iter()
+                .map(|&entity| jitter.interval(base, entity, 0))
+                .collect()
+        };
+        let jitter = |seed| HeartbeatJitter {
+            fraction: 0.2,
+            seed,
+        };
+        let pings = schedule(&jitter(1));
+        assert!(pings
+            .iter()
+            .all(|ping| (base.mul_f32(0.8)..=base.mul_f32(1.2)).contains(ping)));
+        let (first, last) = (pings.iter().min().unwrap(), pings.iter().max().unwrap());
+        assert!(*last - *first > base.mul_f32(0.2), "{pings:?}");
+        // the same seed gives the same schedule, another one a different one
+        assert_eq!(schedule(&jitter(1)), pings);
+        assert_ne!(schedule(&jitter(2)), pings);
+        assert!(schedule(&HeartbeatJitter::default())
+            .iter()
+            .all(|ping| *ping == base));
+    }
+}
@@ -0,0 +1,321 @@
+//! Websocket connections for Bevy, natively over tungstenite and in the browser over the
+//! `WebSocket` API, see [`WebSocketPlugin`].
+
+use std::time::Duration;
+
+use bevy::prelude::*;
+
+mod close;
+mod compression;
+mod connection;
+mod diagnostics;
+pub mod framing;
+mod heartbeat;
+mod outbox;
+mod receive;
+mod reconnect;
+mod reliable;
+mod replication;
+mod send;
+#[cfg(not(target_arch = "wasm32"))]
+mod server;
+#[cfg(test)]
+mod testing;
+#[cfg(target_arch = "wasm32")]
+mod wasm_websocket;
+mod wire_format;
+
+pub use close::*;
+pub use compression::*;
+pub use connection::*;
+pub use diagnostics::*;
+pub use heartbeat::*;
+pub use outbox::*;
+pub use receive::*;
+pub use reconnect::*;
+pub use reliable::*;
+pub use replication::*;
+pub use send::*;
+#[cfg(not(target_arch = "wasm32"))]
+pub use server::*;
+pub use wire_format::*;
+
+#[cfg(target_arch = "wasm32")]
+pub use wasm_websocket::RecvQueueLimit;
+
+/// Everything needed to connect, send and receive, without the demo's scene, input or UI.
+///
+/// A whole round trip against the [`LocalEchoServer`], native only: connect, send some bytes,
+/// get them back, then close.
+///
+/// ```
+/// # use std::time::Duration;
+/// # use bevy::prelude::*;
+/// # use bevy_websocket::*;
+/// let server = LocalEchoServer::start()?;
+/// let mut app = App::new();
+/// app.add_plugins((MinimalPlugins, WebSocketPlugin::default().with_url(server.url())))
+///     .insert_resource(server)
+///     .observe(|opened: Trigger<ConnectionOpened>, mut ev_send: EventWriter<SendTo>| {
+///         let entity = opened.event().entity;
+///         ev_send.send(SendTo { entity, data: b"hello".to_vec() });
+///     })
+///     .add_systems(Update, |mut ev_received: EventReader<WebSocketMessageReceived>,
+///                           mut ev_disconnect: EventWriter<Disconnect>| {
+///         for received in ev_received.read() {
+///             assert_eq!(received.data, b"hello");
+///             ev_disconnect.send(Disconnect {
+///                 entity: received.entity,
+///                 code: CloseCode::Normal,
+///                 reason: "done".to_owned(),
+///             });
+///         }
+///     })
+///     .observe(|_: Trigger<ConnectionClosed>, mut ev_exit: EventWriter<AppExit>| {
+///         ev_exit.send(AppExit::Success);
+///     });
+/// # app.add_systems(Update, |time: Res<Time<Real>>| {
+/// #     assert!(time.elapsed() < Duration::from_secs(10), "no round trip");
+/// # });
+/// app.world_mut().send_event(WebSocketConnectionEvents::SetupConnection(None));
+/// app.run();
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub struct WebSocketPlugin {
+    /// Where [`WebSocketConnectionEvents::SetupConnection`] connects to if it doesn't say
+    url: String,
+    /// How often `send_info` sends the transforms
+    send_interval: Duration,
+    /// See [`WebSocketConfig::heartbeat_interval`]
+    heartbeat_interval: Duration,
+}
+
+impl Default for WebSocketPlugin {
+    fn default() -> Self {
+        Self {
+            url: WebSocketConfig::default().url,
+            send_interval: Duration::from_secs(1),
+            heartbeat_interval: WebSocketConfig::default().heartbeat_interval,
+        }
+    }
+}
+
+impl WebSocketPlugin {
+    pub fn with_url(mut self, url: impl Into<String>) -> Self {
+        self.url = url.into();
+        self
+    }
+
+    pub fn with_send_interval(mut self, send_interval: Duration) -> Self {
+        self.send_interval = send_interval;
+        self
+    }
+
+    pub fn with_heartbeat_interval(mut self, heartbeat_interval: Duration) -> Self {
+        self.heartbeat_interval = heartbeat_interval;
+        self
+    }
+}
+
+impl Plugin for WebSocketPlugin {
+    fn build(&self, app: &mut App) {
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            // fails if the app installed a provider of its own, which is fine too
+            let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+        }
+        app.add_plugins(NetworkDiagnosticsPlugin)
+            .add_systems(Update, setup_connection)
+            .add_systems(Update, handle_tasks)
+            .add_systems(
+                Update,
+                connect_on_first_send
+                    .after(send_info)
+                    .after(send_to)
+                    .after(send_reliable),
+            )
+            .add_systems(Update, report_wasm_open)
+            .add_systems(
+                Update,
+                report_wasm_close
+                    .after(report_wasm_open)
+                    .before(schedule_reconnects),
+            )
+            .add_systems(Update, schedule_reconnects.after(disconnect))
+            .add_systems(Update, reconnect)
+            .add_systems(Update, disconnect)
+            .add_systems(Update, expire_closing.before(schedule_reconnects))
+            .add_systems(Last, close_on_exit.in_set(NetworkShutdown))
+            .add_systems(
+                Update,
+                detect_stalls
+                    .after(recv_info)
+                    .after(flush_outbox)
+                    .before(schedule_reconnects),
+            )
+            .add_event::<WebSocketConnectionEvents>()
+            .add_event::<SendTo>()
+            .add_event::<SendText>()
+            .add_event::<SendFullSnapshot>()
+            .add_event::<SwitchWireFormat>()
+            .add_event::<OutboxBudgetExceeded>()
+            .add_event::<ConnectionOpened>()
+            .add_event::<ConnectionFailed>()
+            .add_event::<ConnectionClosed>()
+            .add_event::<ConnectionPermanentlyFailed>()
+            .add_event::<Disconnect>()
+            .add_event::<WebSocketMessageReceived>()
+            .add_event::<WebSocketMessageBatch>()
+            .add_event::<MessagesDropped>()
+            .add_event::<HeartbeatReceived>()
+            .add_event::<ReplicaUpdate>()
+            .add_event::<SendReliable>()
+            .add_event::<DrainAll>()
+            .add_event::<MessageSendOutcome>()
+            .add_event::<ConnectionsDrained>()
+            .add_event::<ReliableMessageReceived>()
+            .observe(release_outbox_budget)
+            .observe(unregister_replica)
+            .add_systems(Update, send_info.after(tick_adaptive_send_intervals))
+            .add_systems(Update, tick_adaptive_send_intervals)
+            .add_systems(
+                Update,
+                adapt_send_intervals
+                    .after(handle_heartbeats)
+                    .before(tick_adaptive_send_intervals),
+            )
+            .add_systems(Update, send_to)
+            .add_systems(Update, send_text)
+            .add_systems(Update, resubscribe)
+            .add_systems(Update, (resend_unacked, send_reliable).chain())
+            .add_systems(Update, receive_reliable.after(recv_info))
+            .add_systems(Update, log_reliable_messages.after(receive_reliable))
+            .add_systems(Update, send_heartbeats)
+            .add_systems(
+                Update,
+                grant_flow_credit.after(recv_info).before(flush_outbox),
+            )
+            .add_systems(Update, handle_heartbeats.after(recv_info))
+            .add_systems(
+                Update,
+                (enforce_outbox_capacity, flush_outbox)
+                    .chain()
+                    .after(send_info)
+                    .after(send_to)
+                    .after(send_text)
+                    .after(resubscribe)
+                    .after(send_reliable)
+                    .after(receive_reliable)
+                    .after(send_heartbeats)
+                    .after(handle_heartbeats)
+                    .after(roll_bandwidth_window),
+            )
+            .add_systems(Update, roll_bandwidth_window)
+            .add_systems(
+                Update,
+                (
+                    start_drain.before(flush_outbox),
+                    track_drain
+                        .after(flush_outbox)
+                        .run_if(resource_exists::<Draining>),
+                    log_drained.after(track_drain),
+                ),
+            )
+            .add_systems(Update, log_outbox_budget_exceeded)
+            .add_systems(Update, log_send_outcomes.after(flush_outbox))
+            .add_systems(Update, track_buffered_amount.after(flush_outbox))
+            .add_systems(Update, recv_info)
+            .add_systems(
+                Update,
+                apply_remote_transforms
+                    .after(recv_info)
+                    .before(apply_replica_updates),
+            )
+            .add_systems(Update, log_received_batches.after(recv_info))
+            .add_systems(Update, record_replay.after(recv_info))
+            .add_systems(
+                Update,
+                switch_wire_formats.after(recv_info).before(send_info),
+            )
+            .add_systems(Update, apply_pending_wire_formats.after(flush_outbox))
+            .add_systems(Update, log_dropped_messages.after(recv_info))
+            .add_systems(
+                Update,
+                assign_network_ids
+                    .run_if(resource_exists::<AutoNetworkIds>)
+                    .before(send_info),
+            )
+            .add_systems(Update, apply_replica_updates)
+            .add_systems(
+                Update,
+                interpolate_remote_transforms.after(apply_replica_updates),
+            )
+            .add_systems(Update, reap_stale_replicas.after(apply_replica_updates))
+            .add_systems(
+                Update,
+                collect_connection_snapshots.run_if(resource_exists::<ConnectionSnapshots>),
+            )
+            .insert_resource(SendMessageConfig {
+                timer: Timer::new(self.send_interval, TimerMode::Repeating),
+            })
+            .insert_resource(WebSocketConfig {
+                url: self.url.clone(),
+                heartbeat_interval: self.heartbeat_interval,
+                ..default()
+            })
+            .insert_resource(OutboxMemoryBudget::new(64 * 1024 * 1024))
+            .insert_resource(ReplicaTimeout(Duration::from_secs(5)))
+            .init_resource::<RemoteSmoothing>()
+            .init_resource::<ReplicaRegistry>()
+            .init_resource::<DuplicateNetworkIds>()
+            .init_resource::<ReconnectPolicy>()
+            .insert_resource(ReconnectWhileUnfocused(true))
+            .insert_resource(CloseTimeout(Duration::from_secs(5)))
+            .init_resource::<StallTimeout>()
+            .init_resource::<AdaptiveSendConfig>()
+            .init_resource::<MaxOutFrameSize>()
+            .init_resource::<DryRun>()
+            .init_resource::<MaxBufferedAmount>()
+            .init_resource::<LazyConnect>()
+            .init_resource::<HeartbeatJitter>()
+            .init_resource::<SetupPollBudget>()
+            .init_resource::<ResendQueuedOnReconnect>()
+            .init_resource::<ReceiveMode>()
+            .init_resource::<ReplayBuffer>()
+            .init_resource::<SendWhileClosed>()
+            .init_resource::<StrictProtocol>()
+            .init_resource::<Bandwidth>()
+            .init_resource::<SendTransformHook>()
+            .init_resource::<SubscribeMessage>()
+            .init_resource::<RecvTransformHook>();
+        #[cfg(not(target_arch = "wasm32"))]
+        app.add_systems(
+            Update,
+            accept_connections.run_if(resource_exists::<WebSocketServer>),
+        )
+        .add_systems(Update, handle_server_handshakes)
+        .add_systems(
+            Update,
+            log_rejected_connections.after(handle_server_handshakes),
+        )
+        .add_event::<ConnectionRejected>()
+        .init_resource::<ReadThread>()
+        .add_systems(
+            Update,
+            spawn_read_threads
+                .run_if(resource_exists::<ReadThread>)
+                // so there's no frame where both read, and messages stay in order
+                .after(recv_info),
+        );
+        #[cfg(debug_assertions)]
+        app.add_event::<InjectInboundMessage>();
+        #[cfg(feature = "telemetry")]
+        app.add_systems(
+            Update,
+            emit_telemetry
+                .after(flush_outbox)
+                .after(recv_info)
+                .after(handle_tasks),
+        );
+    }
+}
@@ -1,278 +1,401 @@
-use std::{
-    io::ErrorKind,
-    net::TcpStream,
-    sync::{Arc, Mutex},
-    time::Duration,
-};
+//! The demo: a scene whose falling cube is replicated over a websocket with [`WebSocketPlugin`].
+//!
+//! Space connects, Escape cancels pending connections, D disconnects open ones, F sends a
+//! full snapshot, J toggles between bincode and JSON. Natively, S starts a server other
+//! instances can connect to and E points new connections at a local echo server.
 
 use avian3d::prelude::*; // completely unnecessary but I like physics;
-use bevy::{
-    ecs::world::CommandQueue,
-    prelude::*,
-    tasks::{block_on, futures_lite::future, AsyncComputeTaskPool, Task},
-};
+use bevy::{input::common_conditions::input_just_pressed, prelude::*};
+#[cfg(feature = "perf-ui")]
 use iyes_perf_ui::{entries::PerfUiBundle, PerfUiPlugin};
+use serde::{Deserialize, Serialize};
 
-#[cfg(not(target_arch = "wasm32"))]
-use tungstenite::{connect, http::Response, stream::MaybeTlsStream, Message, WebSocket};
+use bevy_websocket::*;
 
 fn main() {
-    #[cfg(not(target_arch = "wasm32"))]
-    {
-        rustls::crypto::aws_lc_rs::default_provider()
-            .install_default()
-            .expect("Failed to install rustls crypto provider");
-    }
-    App::new()
-        .add_plugins(DefaultPlugins)
-        .add_plugins(PerfUiPlugin)
+    let mut app = App::new();
+    app.add_plugins(DefaultPlugins);
+    #[cfg(feature = "perf-ui")]
+    app.add_plugins(PerfUiPlugin)
         .add_plugins(bevy::diagnostic::FrameTimeDiagnosticsPlugin)
         .add_plugins(bevy::diagnostic::EntityCountDiagnosticsPlugin)
-        .add_plugins(bevy::diagnostic::SystemInformationDiagnosticsPlugin)
+        .add_plugins(bevy::diagnostic::SystemInformationDiagnosticsPlugin);
+    app.add_plugins(WebSocketPlugin::default())
         .add_plugins(PhysicsPlugins::default())
+        .add_plugins(PhysicsReplicationPlugin)
         .add_systems(Startup, setup_scene)
         .add_systems(Update, check_connection_input)
-        .add_systems(Update, setup_connection)
-        .add_systems(Update, handle_tasks)
-        .add_event::<WebSocketConnectionEvents>()
-        .add_systems(Update, send_info)
-        .add_systems(Update, recv_info)
-        .insert_resource(SendMessageConfig {
-            timer: Timer::new(Duration::from_secs(1), TimerMode::Repeating),
-        })
-        .run();
+        .add_systems(
+            Update,
+            cancel_all_pending_connections
+                .before(handle_tasks)
+                .run_if(input_just_pressed(KeyCode::Escape)),
+        )
+        .add_systems(
+            Update,
+            disconnect_all
+                .before(disconnect)
+                .run_if(input_just_pressed(KeyCode::KeyD)),
+        )
+        .add_systems(Update, request_full_snapshots.before(send_info))
+        .add_systems(Update, toggle_wire_format.before(switch_wire_formats))
+        .add_systems(Update, log_received.after(recv_info))
+        .add_systems(Update, show_replicas.after(apply_replica_updates))
+        .add_systems(
+            Update,
+            update_connection_panel
+                .after(collect_connection_snapshots)
+                .run_if(resource_exists::<ConnectionSnapshots>),
+        )
+        .init_resource::<ConnectionSnapshots>(); // remove to turn off the connection panel
+    #[cfg(not(target_arch = "wasm32"))]
+    app.add_systems(
+        Update,
+        start_server.run_if(input_just_pressed(KeyCode::KeyS)),
+    )
+    .add_systems(
+        Update,
+        use_local_echo_server.run_if(input_just_pressed(KeyCode::KeyE)),
+    );
+    app.run();
 }
 
-#[cfg(target_arch = "wasm32")]
-mod wasm_websocket {
-    use std::{cell::RefCell, collections::VecDeque, rc::Rc};
-
-    use bevy::log::info;
-    use web_sys::{
-        js_sys::{ArrayBuffer, Uint8Array},
-        wasm_bindgen::{prelude::Closure, JsCast},
-        BinaryType, Event, MessageEvent,
-    };
-
-    pub struct Client {
-        pub socket: web_sys::WebSocket,
-        pub recv_queue: Rc<RefCell<VecDeque<Vec<u8>>>>,
-        _open_cb: Closure<dyn FnMut(Event)>,
-        _message_cb: Closure<dyn FnMut(MessageEvent)>,
+fn check_connection_input(
+    input: Res<ButtonInput<KeyCode>>,
+    mut ev_connect: EventWriter<WebSocketConnectionEvents>,
+) {
+    if input.just_pressed(KeyCode::Space) {
+        // set up connection
+        ev_connect.send(WebSocketConnectionEvents::SetupConnection(None));
     }
+}
 
-    impl Client {
-        pub fn new(url: &str) -> send_wrapper::SendWrapper<Self> {
-            info!("Opening wasm websocket");
-            let recv_queue = Rc::new(RefCell::new(VecDeque::new()));
-            let socket = web_sys::WebSocket::new(url).expect("Failed to create WebSocket object");
-            socket.set_binary_type(BinaryType::Arraybuffer);
-            let open_cb: Closure<dyn FnMut(_)> = Closure::new(|_event: Event| {
-                web_sys::console::log_1(&"Connection opened".into());
-            });
-            socket
-                .add_event_listener_with_callback("open", open_cb.as_ref().dyn_ref().unwrap())
-                .unwrap();
-            let message_cb: Closure<dyn FnMut(_)> = Closure::new({
-                let recv_queue = Rc::clone(&recv_queue);
-                move |event: MessageEvent| {
-                    web_sys::console::log_1(&format!("Got message: {:?}", event.data()).into());
-                    if let Some(buf) = event.data().dyn_ref::<ArrayBuffer>() {
-                        recv_queue
-                            .borrow_mut()
-                            .push_back(Uint8Array::new(buf).to_vec());
-                    }
-                }
-            });
-            socket
-                .add_event_listener_with_callback("message", message_cb.as_ref().dyn_ref().unwrap())
-                .unwrap();
-            send_wrapper::SendWrapper::new(Client {
-                socket,
-                recv_queue,
-                _open_cb: open_cb,
-                _message_cb: message_cb,
-            })
+/// Point new connections at a [`LocalEchoServer`], starting it first
+#[cfg(not(target_arch = "wasm32"))]
+fn use_local_echo_server(
+    mut commands: Commands,
+    server: Option<Res<LocalEchoServer>>,
+    mut config: ResMut<WebSocketConfig>,
+) {
+    if server.is_some() {
+        return;
+    }
+    match LocalEchoServer::start() {
+        Ok(server) => {
+            info!("Local echo server listening on {}", server.url());
+            config.url = server.url().to_owned();
+            commands.insert_resource(server);
         }
+        Err(e) => warn!("Could not start the local echo server: {e}"),
     }
 }
 
-#[derive(Component)]
-struct WebSocketClient(
-    #[cfg(target_arch = "wasm32")] send_wrapper::SendWrapper<wasm_websocket::Client>,
-    #[cfg(not(target_arch = "wasm32"))]
-    (
-        WebSocket<MaybeTlsStream<TcpStream>>,
-        Response<Option<Vec<u8>>>,
-    ),
-);
+/// Start listening for peers, so two instances of the example can talk to each other
+#[cfg(not(target_arch = "wasm32"))]
+fn start_server(mut commands: Commands, server: Option<Res<WebSocketServer>>) {
+    if server.is_some() {
+        return;
+    }
+    match WebSocketServer::bind("127.0.0.1:9001") {
+        Ok(server) => {
+            info!("Listening on ws://127.0.0.1:9001/");
+            commands.insert_resource(server);
+        }
+        Err(e) => warn!("Could not start the server: {e}"),
+    }
+}
 
-#[derive(Event)]
-enum WebSocketConnectionEvents {
-    SetupConnection,
+fn request_full_snapshots(
+    input: Res<ButtonInput<KeyCode>>,
+    connections: Query<Entity, With<Outbox>>,
+    mut ev_full: EventWriter<SendFullSnapshot>,
+) {
+    if input.just_pressed(KeyCode::KeyF) {
+        ev_full.send_batch(connections.iter().map(|entity| SendFullSnapshot { entity }));
+    }
 }
 
-fn check_connection_input(
+/// Toggle every connection between bincode and JSON
+fn toggle_wire_format(
     input: Res<ButtonInput<KeyCode>>,
-    mut ev_connect: EventWriter<WebSocketConnectionEvents>,
+    connections: Query<(Entity, Option<&WireFormat>), With<Outbox>>,
+    mut ev_switch: EventWriter<SwitchWireFormat>,
 ) {
-    if input.just_pressed(KeyCode::Space) {
-        // set up connection
-        ev_connect.send(WebSocketConnectionEvents::SetupConnection);
+    if !input.just_pressed(KeyCode::KeyJ) {
+        return;
+    }
+    for (entity, format) in &connections {
+        let format = match format.copied().unwrap_or_default() {
+            WireFormat::Bincode => WireFormat::Json,
+            WireFormat::Json => WireFormat::Bincode,
+        };
+        ev_switch.send(SwitchWireFormat { entity, format });
     }
 }
 
-use thiserror::Error;
-use web_sys::MessageEvent;
-#[cfg(target_arch = "wasm32")]
-use web_sys::{
-    wasm_bindgen::{prelude::Closure, JsCast, JsValue},
-    Event,
-};
+fn log_received(
+    mut ev_received: EventReader<WebSocketMessageReceived>,
+    hook: Res<RecvTransformHook>,
+    formats: Query<&WireFormat>,
+) {
+    for WebSocketMessageReceived {
+        entity,
+        channel,
+        data,
+        text,
+    } in ev_received.read()
+    {
+        if *text {
+            info!(
+                "Received text from {entity}: {}",
+                String::from_utf8_lossy(data)
+            );
+            continue;
+        }
+        if channel.is_some_and(|channel| channel != GAME_STATE_CHANNEL)
+            || data.starts_with(&PHYSICS_STATE_TAG)
+            || data.starts_with(&RELIABLE_DATA_TAG)
+            || data.starts_with(&RELIABLE_ACK_TAG)
+        {
+            continue;
+        }
+        let format = formats.get(*entity).copied().unwrap_or_default();
+        match decode_transforms(format, data) {
+            Some(transforms) => {
+                let transforms = transforms
+                    .iter()
+                    .map(|(id, transform)| (id.0, (hook.0)(transform)))
+                    .collect::<Vec<_>>();
+                info!("Received transforms from {entity}: {transforms:?}")
+            }
+            None => info!("Received message from {entity}: {data:?}"),
+        }
+    }
+}
 
-#[derive(Error, Debug)]
-enum ConnectionSetupError {
-    #[error("IO")]
-    Io(#[from] std::io::Error),
-    #[cfg(target_arch = "wasm32")]
-    #[error("WebSocket")]
-    WebSocket(), // TODO: remove or fill in actual error and do error handling with it?
-    #[cfg(not(target_arch = "wasm32"))]
-    #[error("WebSocket")]
-    WebSocket(#[from] tungstenite::Error),
+/// Show the [`RemoteReplica`]s as small cubes
+fn show_replicas(
+    mut commands: Commands,
+    replicas: Query<Entity, Added<RemoteReplica>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut look: Local<Option<(Handle<Mesh>, Handle<StandardMaterial>)>>,
+) {
+    for entity in replicas.iter() {
+        let (mesh, material) = look
+            .get_or_insert_with(|| {
+                (
+                    meshes.add(Cuboid::new(0.5, 0.5, 0.5)),
+                    materials.add(Color::srgb_u8(255, 144, 124)),
+                )
+            })
+            .clone();
+        commands.entity(entity).insert((mesh, material));
+    }
 }
 
+/// Text showing the [`ConnectionSnapshots`], as an example of a connection debug panel
 #[derive(Component)]
-struct WebSocketConnectionSetupTask(
-    #[allow(unused)] Task<Result<CommandQueue, ConnectionSetupError>>,
-);
+struct ConnectionPanel;
 
-fn setup_connection(
-    mut ev_connect: EventReader<WebSocketConnectionEvents>,
-    mut commands: Commands,
+fn update_connection_panel(
+    snapshots: Res<ConnectionSnapshots>,
+    bandwidth: Res<Bandwidth>,
+    mut panels: Query<&mut Text, With<ConnectionPanel>>,
 ) {
-    for ev in ev_connect.read() {
-        match ev {
-            WebSocketConnectionEvents::SetupConnection => {
-                info!("Setting up connection!");
-                let url = "wss://echo.websocket.org/";
-                let entity = commands.spawn_empty().id();
-                #[cfg(not(target_arch = "wasm32"))]
-                {
-                    let pool = AsyncComputeTaskPool::get();
-                    let task = pool.spawn(async move {
-                        let mut client = connect(url)?;
-                        match client.0.get_mut() {
-                            MaybeTlsStream::Plain(p) => p.set_nonblocking(true)?,
-                            MaybeTlsStream::Rustls(stream_owned) => {
-                                stream_owned.get_mut().set_nonblocking(true)?
-                            }
-                            _ => todo!(),
-                        };
-                        info!("Connected successfully!");
-                        let mut command_queue = CommandQueue::default();
+    let mut lines = vec![format!(
+        "{} B/s sent, {} B/s received",
+        bandwidth.sent_per_second, bandwidth.received_per_second
+    )];
+    for ConnectionSnapshot {
+        entity,
+        name,
+        state,
+        reconnecting,
+        rtt,
+        queued: (messages, bytes),
+        reconnect_attempts,
+        secure,
+    } in &snapshots.0
+    {
+        let lock = if *secure == Some(true) {
+            " (secure)"
+        } else {
+            ""
+        };
+        let rtt = rtt.map_or_else(|| "-".to_owned(), |rtt| format!("{rtt:?}"));
+        let reconnecting = if *reconnecting { " (reconnecting)" } else { "" };
+        lines.push(format!(
+            "{entity} {name}{lock}: {state:?}{reconnecting}, rtt {rtt}, {messages} queued ({bytes} B), {reconnect_attempts} reconnects"
+        ));
+    }
+    for mut text in &mut panels {
+        text.sections[0].value = lines.join("\n");
+    }
+}
 
-                        command_queue.push(move |world: &mut World| {
-                            world
-                                .entity_mut(entity)
-                                .insert(WebSocketClient(client))
-                                // Task is complete, so remove task component from entity
-                                .remove::<WebSocketConnectionSetupTask>();
-                        });
+/// Replicates the dynamic avian3d bodies over every connection we open: their state goes out
+/// at the pace of [`send_info`] and what peers send lands on replicas through
+/// [`ReplicaUpdate`]s
+struct PhysicsReplicationPlugin;
 
-                        Ok(command_queue)
-                    });
-                    commands
-                        .entity(entity)
-                        .insert(WebSocketConnectionSetupTask(task));
-                }
-                #[cfg(target_arch = "wasm32")]
-                {
-                    commands
-                        .entity(entity)
-                        .insert(WebSocketClient(wasm_websocket::Client::new(url)));
-                }
-            }
-        }
+impl Plugin for PhysicsReplicationPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(
+            Update,
+            (replicate_physics_on_new_connections, send_physics_state)
+                .chain()
+                .after(send_info)
+                .after(assign_network_ids)
+                .before(flush_outbox),
+        )
+        .add_systems(
+            Update,
+            receive_physics_state
+                .after(recv_info)
+                .before(apply_replica_updates),
+        );
     }
 }
 
-fn handle_tasks(
+/// Send the state of dynamic physics bodies on this connection, see [`send_physics_state`]
+#[derive(Component)]
+struct ReplicatePhysics;
+
+/// Opt every connection we open into [`ReplicatePhysics`], peers connecting to our server
+/// aren't
+fn replicate_physics_on_new_connections(
     mut commands: Commands,
-    mut transform_tasks: Query<&mut WebSocketConnectionSetupTask>,
+    connections: Query<Entity, Added<ServerUrl>>,
 ) {
-    for mut task in &mut transform_tasks {
-        if let Some(result) = block_on(future::poll_once(&mut task.0)) {
-            // append the returned command queue to have it execute later
-            match result {
-                Ok(mut commands_queue) => {
-                    commands.append(&mut commands_queue);
-                }
-                Err(e) => {
-                    info!("Connection failed with: {e:?}");
-                }
-            }
-        }
+    for entity in &connections {
+        commands.entity(entity).insert(ReplicatePhysics);
     }
 }
 
-#[derive(Resource)]
-struct SendMessageConfig {
-    timer: Timer,
+/// What [`ReplicatePhysics`] sends for each dynamic body
+#[derive(Serialize, Deserialize, Debug)]
+struct PhysicsState {
+    id: NetworkId,
+    transform: Transform,
+    linear_velocity: Vec3,
+    angular_velocity: Vec3,
 }
 
-fn send_info(
-    some_data: Query<(&Transform,)>,
-    time: Res<Time>,
-    mut entities_with_client: Query<(&mut WebSocketClient,)>,
-    mut config: ResMut<SendMessageConfig>,
+/// Marks messages carrying a `Vec<PhysicsState>`
+const PHYSICS_STATE_TAG: [u8; 4] = [0xff, b'p', b'h', b'y'];
+
+#[allow(clippy::type_complexity)]
+fn send_physics_state(
+    bodies: Query<(
+        Entity,
+        Option<&NetworkId>,
+        &RigidBody,
+        &Transform,
+        &LinearVelocity,
+        &AngularVelocity,
+    )>,
+    config: Res<SendMessageConfig>,
+    mut connections: Query<
+        (
+            Entity,
+            &mut Outbox,
+            Option<&mut BinaryDiff>,
+            Option<&Compression>,
+            Option<&WireFormat>,
+            Has<Multiplexed>,
+            Option<&AdaptiveSendInterval>,
+            Option<&ReplicationScope>,
+        ),
+        (With<ReplicatePhysics>, Without<Closing>),
+    >,
+    hook: Res<SendTransformHook>,
+    mut budget: ResMut<OutboxMemoryBudget>,
+    mut ev_exceeded: EventWriter<OutboxBudgetExceeded>,
+    tap: Option<Res<WireTap>>,
 ) {
-    config.timer.tick(time.delta());
-    if config.timer.finished() {
-        // only send messages once every second, so we don't spam the server
-        info!("Time to send data again...");
-        for (mut client,) in entities_with_client.iter_mut() {
-            let transforms = &some_data.iter().map(|x| x.0.clone()).collect::<Vec<_>>();
-            info!("Sending data: {transforms:?}");
-            let msg = bincode::serialize(transforms).unwrap();
-            #[cfg(target_arch = "wasm32")]
-            {
-                // TODO: do some handling so we know whether the websocket is connected yet
-                let _ = client.0.socket.send_with_u8_array(msg.as_slice()); // ignore the error because the websocket may still be connecting
-            }
-            #[cfg(not(target_arch = "wasm32"))]
-            {
-                match client.0 .0.send(Message::Binary(msg)) {
-                    Ok(_) => info!("Data successfully sent!"),
-                    #[cfg(not(target_arch = "wasm32"))]
-                    Err(tungstenite::Error::Io(e)) if e.kind() == ErrorKind::WouldBlock => { /* ignore */
-                    }
-                    Err(e) => {
-                        warn!("Could not send the message: {e:?}");
-                    }
-                }
-            }
+    if connections.is_empty() {
+        return;
+    }
+    let states = bodies
+        .iter()
+        .filter(|(_, _, body, ..)| body.is_dynamic())
+        .map(|(entity, id, _, transform, linear, angular)| {
+            let state = PhysicsState {
+                id: NetworkId::or_entity(id, entity),
+                transform: (hook.0)(transform),
+                linear_velocity: linear.0,
+                angular_velocity: angular.0,
+            };
+            (entity, state)
+        })
+        .collect::<Vec<_>>();
+    for (entity, mut outbox, diff, compression, format, multiplexed, adaptive, scope) in
+        &mut connections
+    {
+        // same pace as `send_info`, which ticks the timer
+        if !config.is_due(adaptive) {
+            continue;
         }
+        let states = states
+            .iter()
+            .filter(|(body, _)| scope.is_none_or(|scope| scope.0.contains(body)))
+            .map(|(_, state)| state)
+            .collect::<Vec<_>>();
+        let msg = [
+            &PHYSICS_STATE_TAG[..],
+            &format.copied().unwrap_or_default().encode(&states),
+        ]
+        .concat();
+        let channel = multiplexed.then_some(GAME_STATE_CHANNEL);
+        enqueue(
+            entity,
+            channel,
+            msg,
+            &mut outbox,
+            diff,
+            compression,
+            &mut budget,
+            &mut ev_exceeded,
+            tap.as_deref(),
+        );
     }
 }
 
-fn recv_info(mut q: Query<(&mut WebSocketClient,)>) {
-    for (mut client,) in q.iter_mut() {
-        #[cfg(not(target_arch = "wasm32"))]
-        {
-            match client.0 .0.read() {
-                Ok(m) => info!("Received message {m:?}"),
-                Err(tungstenite::Error::Io(e)) if e.kind() == ErrorKind::WouldBlock => { /* ignore */
-                }
-                Err(e) => warn!("error receiving: {e}"),
-            }
-        }
-        #[cfg(target_arch = "wasm32")]
-        {
-            while let Some(m) = client.0.recv_queue.borrow_mut().pop_front() {
-                info!("Received message {m:?}")
-            }
+/// Turn received [`PhysicsState`]s into [`ReplicaUpdate`]s
+fn receive_physics_state(
+    mut ev_received: EventReader<WebSocketMessageReceived>,
+    formats: Query<&WireFormat>,
+    hook: Res<RecvTransformHook>,
+    duplicates: Res<DuplicateNetworkIds>,
+    mut ev_update: EventWriter<ReplicaUpdate>,
+) {
+    for WebSocketMessageReceived { entity, data, .. } in ev_received.read() {
+        let Some(data) = data.strip_prefix(&PHYSICS_STATE_TAG[..]) else {
+            continue;
+        };
+        let format = formats.get(*entity).copied().unwrap_or_default();
+        let Some(states) = format.decode::<Vec<PhysicsState>>(data) else {
+            warn!("Could not decode physics state from {entity}");
+            continue;
+        };
+        let received = states.len();
+        let states = duplicates.dedup(states, |state| state.id);
+        if states.len() < received {
+            warn!(
+                "{entity} sent duplicate network ids, dropped {} states ({:?})",
+                received - states.len(),
+                *duplicates
+            );
         }
+        ev_update.send_batch(states.into_iter().map(|state| ReplicaUpdate {
+            id: state.id,
+            transform: (hook.0)(&state.transform),
+            velocity: Some((
+                LinearVelocity(state.linear_velocity),
+                AngularVelocity(state.angular_velocity),
+            )),
+        }));
     }
 }
 
@@ -282,7 +405,17 @@ fn setup_scene(
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
 ) {
+    #[cfg(feature = "perf-ui")]
     commands.spawn(PerfUiBundle::default());
+    commands.spawn((
+        TextBundle::from_section("", TextStyle::default()).with_style(Style {
+            position_type: PositionType::Absolute,
+            bottom: Val::Px(5.0),
+            left: Val::Px(5.0),
+            ..default()
+        }),
+        ConnectionPanel,
+    ));
 
     // circular base
     commands
@@ -303,7 +436,11 @@ fn setup_scene(
             transform: Transform::from_xyz(0.0, 2.5, 0.0),
             ..default()
         })
-        .insert((RigidBody::Dynamic, Collider::cuboid(1.0, 1.0, 1.0)));
+        .insert((
+            RigidBody::Dynamic,
+            Collider::cuboid(1.0, 1.0, 1.0),
+            NetworkId(1),
+        ));
     // light
     commands.spawn(PointLightBundle {
         point_light: PointLight {
@@ -319,3 +456,74 @@ fn setup_scene(
         ..default()
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bevy::time::TimeUpdateStrategy;
+
+    use super::*;
+
+    #[test]
+    fn physics_state_goes_out_and_lands_on_replicas() {
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, WebSocketPlugin::default()))
+            .add_plugins(PhysicsReplicationPlugin)
+            .insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_millis(
+                100,
+            )));
+        // never connected, so the outbox keeps whatever is sent
+        let connection = app
+            .world_mut()
+            .spawn((Outbox::default(), ReplicatePhysics))
+            .id();
+        let transform = Transform::from_xyz(1.0, 2.0, 3.0);
+        app.world_mut().spawn((
+            RigidBody::Dynamic,
+            transform,
+            LinearVelocity(Vec3::X),
+            AngularVelocity(Vec3::Y),
+            NetworkId(5),
+        ));
+        app.world_mut().spawn((
+            RigidBody::Static,
+            Transform::default(),
+            LinearVelocity::default(),
+            AngularVelocity::default(),
+            NetworkId(6),
+        ));
+        let payload = loop {
+            app.update();
+            let outbox = app.world().get::<Outbox>(connection).unwrap();
+            let physics = outbox
+                .queued()
+                .find(|msg| msg.starts_with(&PHYSICS_STATE_TAG));
+            if let Some(payload) = physics {
+                break payload.to_vec();
+            }
+        };
+        let states = WireFormat::Bincode
+            .decode::<Vec<PhysicsState>>(&payload[PHYSICS_STATE_TAG.len()..])
+            .unwrap();
+        assert_eq!(states.len(), 1, "{states:?}");
+        assert_eq!(states[0].id, NetworkId(5));
+        assert_eq!(states[0].transform, transform);
+        assert_eq!(states[0].linear_velocity, Vec3::X);
+        assert_eq!(states[0].angular_velocity, Vec3::Y);
+
+        app.world_mut().send_event(InjectInboundMessage {
+            entity: connection,
+            data: payload,
+        });
+        app.update();
+        let updates: Vec<_> = app
+            .world_mut()
+            .resource_mut::<Events<ReplicaUpdate>>()
+            .drain()
+            .map(|update| (update.id, update.transform, update.velocity))
+            .collect();
+        let velocity = Some((LinearVelocity(Vec3::X), AngularVelocity(Vec3::Y)));
+        assert_eq!(updates, [(NetworkId(5), transform, velocity)]);
+    }
+}
@@ -0,0 +1,1247 @@
+//! Queueing outgoing messages in each connection's [`Outbox`] and writing them to the socket.
+
+use std::{
+    collections::VecDeque,
+    hash::{Hash, Hasher},
+    sync::mpsc,
+    time::Duration,
+};
+
+use bevy::{prelude::*, utils::HashSet};
+use thiserror::Error;
+
+use crate::{
+    parse_heartbeat, Channel, CloseCode, Closing, Disconnect, IoActivity, NetworkStats,
+    WebSocketClient,
+};
+
+#[cfg(not(target_arch = "wasm32"))]
+use std::{io::ErrorKind, net::TcpStream};
+#[cfg(not(target_arch = "wasm32"))]
+use tungstenite::{
+    protocol::frame::{
+        coding::{Data, OpCode},
+        Frame,
+    },
+    stream::MaybeTlsStream,
+    Message, WebSocket,
+};
+
+/// Messages waiting to be written to a connection's socket
+#[derive(Component, Default)]
+pub struct Outbox {
+    pub(crate) queue: VecDeque<QueuedMessage>,
+    bytes: usize,
+}
+
+pub struct QueuedMessage {
+    pub(crate) data: Vec<u8>,
+    /// Send it as a text message, `data` is valid UTF-8 then
+    text: bool,
+    /// Bytes of `data` already written as fragments, see [`MaxOutFrameSize`]
+    sent: usize,
+    on_flushed: Option<FlushSignal>,
+}
+
+/// Resolves once a message was handed to the socket, or failed to be
+pub type FlushSignal = mpsc::Sender<Result<(), FlushError>>;
+
+#[derive(Error, Debug, Clone)]
+pub enum FlushError {
+    #[error("the connection closed before the message was sent")]
+    ConnectionClosed,
+    #[error("sending failed: {0}")]
+    Send(String),
+    #[error("dropped to make room in the full outbox")]
+    Overflowed,
+}
+
+impl QueuedMessage {
+    fn resolve(&self, result: Result<(), FlushError>) {
+        if let Some(on_flushed) = &self.on_flushed {
+            // the receiver may not care anymore, that's fine
+            let _ = on_flushed.send(result);
+        }
+    }
+}
+
+impl Outbox {
+    /// The messages waiting to be written, oldest first and as they'll go out, e.g. to check
+    /// what a connection that isn't connected yet would send
+    pub fn queued(&self) -> impl Iterator<Item = &[u8]> {
+        self.queue.iter().map(|msg| msg.data.as_slice())
+    }
+
+    /// Messages waiting to be written, including one that's only partly written as fragments
+    pub fn pending_count(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Bytes of all messages waiting to be written, see [`WebSocketClient::buffered_bytes`]
+    /// for what the socket itself still holds
+    pub fn pending_bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Queue `msg`, or hand it back if that would go over the global budget
+    pub fn push(&mut self, msg: Vec<u8>, budget: &mut OutboxMemoryBudget) -> Result<(), Vec<u8>> {
+        self.push_queued(msg, None, budget)
+    }
+
+    /// Like [`Outbox::push`], but also returns a receiver that tells when `flush_outbox` wrote
+    /// the message, e.g. to only start a dependent operation afterwards
+    pub fn push_with_ack(
+        &mut self,
+        msg: Vec<u8>,
+        budget: &mut OutboxMemoryBudget,
+    ) -> Result<mpsc::Receiver<Result<(), FlushError>>, Vec<u8>> {
+        let (on_flushed, flushed) = mpsc::channel();
+        self.push_queued(msg, Some(on_flushed), budget)?;
+        Ok(flushed)
+    }
+
+    fn push_queued(
+        &mut self,
+        data: Vec<u8>,
+        on_flushed: Option<FlushSignal>,
+        budget: &mut OutboxMemoryBudget,
+    ) -> Result<(), Vec<u8>> {
+        if budget.used + data.len() > budget.limit {
+            return Err(data);
+        }
+        budget.used += data.len();
+        self.bytes += data.len();
+        self.queue.push_back(QueuedMessage {
+            data,
+            text: false,
+            sent: 0,
+            on_flushed,
+        });
+        Ok(())
+    }
+
+    /// Queue `text` as a text message, or hand it back if that would go over the global budget
+    pub(crate) fn push_text(
+        &mut self,
+        text: String,
+        budget: &mut OutboxMemoryBudget,
+    ) -> Result<(), String> {
+        // it was a `String` a moment ago, so this can't fail
+        self.push_queued(text.into_bytes(), None, budget)
+            .map_err(|data| String::from_utf8(data).unwrap())?;
+        self.queue.back_mut().unwrap().text = true;
+        Ok(())
+    }
+
+    /// Queue `msg` on a logical channel of a [`Multiplexed`](crate::Multiplexed) connection.
+    ///
+    /// The frame is sent as is, so this doesn't combine with [`BinaryDiff`](crate::BinaryDiff).
+    pub fn send_on(
+        &mut self,
+        channel: Channel,
+        msg: &[u8],
+        budget: &mut OutboxMemoryBudget,
+    ) -> Result<(), Vec<u8>> {
+        let mut frame = Vec::with_capacity(msg.len() + 1);
+        frame.push(channel);
+        frame.extend_from_slice(msg);
+        self.push(frame, budget)
+    }
+
+    /// Account for a message that left the queue for good (written or dropped)
+    pub(crate) fn release(&mut self, len: usize, budget: &mut OutboxMemoryBudget) {
+        self.bytes -= len;
+        budget.used -= len;
+    }
+}
+
+/// How many messages one connection's [`Outbox`] holds before the oldest ones give way, so a
+/// connection that can't keep up (the socket keeps saying `WouldBlock`) sends recent state
+/// instead of an ever growing backlog. Connections without it queue until the
+/// [`OutboxMemoryBudget`] runs out.
+#[derive(Component)]
+pub struct OutboxCapacity {
+    max_messages: usize,
+    overflow: OutboxOverflow,
+}
+
+impl Default for OutboxCapacity {
+    fn default() -> Self {
+        Self {
+            max_messages: 1024,
+            overflow: OutboxOverflow::DropOldest,
+        }
+    }
+}
+
+/// What to do when an [`Outbox`] has more messages than its [`OutboxCapacity`]
+#[derive(Clone, Copy)]
+pub enum OutboxOverflow {
+    /// Drop the oldest messages that haven't started going out, with a warning
+    DropOldest,
+    /// Close the connection with [`CloseCode::Error`], e.g. when every message matters and a
+    /// reconnect with a fresh state is better than gaps
+    Disconnect,
+}
+
+/// Trim every outbox to its [`OutboxCapacity`] right before [`flush_outbox`], so anything
+/// queued this frame counts
+pub fn enforce_outbox_capacity(
+    mut q: Query<(Entity, &mut Outbox, &OutboxCapacity), Without<Closing>>,
+    mut budget: ResMut<OutboxMemoryBudget>,
+    mut ev_outcome: EventWriter<MessageSendOutcome>,
+    mut ev_disconnect: EventWriter<Disconnect>,
+) {
+    for (entity, mut outbox, capacity) in &mut q {
+        let excess = outbox.queue.len().saturating_sub(capacity.max_messages);
+        if excess == 0 {
+            continue;
+        }
+        match capacity.overflow {
+            OutboxOverflow::DropOldest => {
+                // a message that's partly out as fragments has to be finished first
+                let skip = usize::from(outbox.queue.front().is_some_and(|msg| msg.sent > 0));
+                let excess = excess.min(outbox.queue.len() - skip);
+                for msg in outbox.queue.drain(skip..skip + excess).collect::<Vec<_>>() {
+                    outbox.release(msg.data.len(), &mut budget);
+                    msg.resolve(Err(FlushError::Overflowed));
+                    ev_outcome.send(MessageSendOutcome {
+                        entity,
+                        bytes: msg.data.len(),
+                        outcome: SendOutcome::Failed(FlushError::Overflowed.to_string()),
+                    });
+                }
+                warn!("Outbox of {entity} is full, dropped its {excess} oldest messages");
+            }
+            OutboxOverflow::Disconnect => {
+                ev_disconnect.send(Disconnect {
+                    entity,
+                    code: CloseCode::Error,
+                    reason: "falling behind on sending".to_owned(),
+                });
+            }
+        }
+    }
+}
+
+/// Upper bound on the bytes queued in all outboxes together.
+///
+/// This protects against running out of memory when many connections stall at once;
+/// messages that don't fit are refused and reported with [`OutboxBudgetExceeded`].
+#[derive(Resource)]
+pub struct OutboxMemoryBudget {
+    limit: usize,
+    used: usize,
+}
+
+impl OutboxMemoryBudget {
+    pub fn new(limit: usize) -> Self {
+        Self { limit, used: 0 }
+    }
+}
+
+#[derive(Event)]
+pub struct OutboxBudgetExceeded {
+    pub entity: Entity,
+    pub refused_bytes: usize,
+}
+
+/// Give back the budget of whatever was still queued when a connection goes away
+pub fn release_outbox_budget(
+    trigger: Trigger<OnRemove, Outbox>,
+    outboxes: Query<&Outbox>,
+    mut budget: ResMut<OutboxMemoryBudget>,
+) {
+    if let Ok(outbox) = outboxes.get(trigger.entity()) {
+        budget.used -= outbox.bytes;
+        for msg in &outbox.queue {
+            msg.resolve(Err(FlushError::ConnectionClosed));
+        }
+    }
+}
+
+pub fn log_outbox_budget_exceeded(mut ev_exceeded: EventReader<OutboxBudgetExceeded>) {
+    for ev in ev_exceeded.read() {
+        warn!(
+            "Outbox memory budget exceeded, dropped {} bytes for {}",
+            ev.refused_bytes, ev.entity
+        );
+    }
+}
+
+/// Flush every open connection and report once all outboxes are empty, e.g. to get the last
+/// updates out before unloading a scene. Reported with [`ConnectionsDrained`].
+#[derive(Event)]
+pub struct DrainAll {
+    /// Give up on connections that still have queued messages after this long
+    pub timeout: Duration,
+}
+
+#[derive(Event, Clone, Debug)]
+pub struct ConnectionsDrained {
+    /// Connections that timed out with messages still queued or were lost while draining
+    pub failed: Vec<Entity>,
+}
+
+/// A [`DrainAll`] in progress
+#[derive(Resource)]
+pub struct Draining {
+    deadline: Duration,
+    pending: HashSet<Entity>,
+    failed: Vec<Entity>,
+}
+
+pub fn start_drain(
+    mut commands: Commands,
+    mut ev_drain: EventReader<DrainAll>,
+    connections: Query<Entity, (With<WebSocketClient>, With<Outbox>)>,
+    time: Res<Time>,
+) {
+    // the latest request wins, a drain already in progress starts over
+    let Some(DrainAll { timeout }) = ev_drain.read().last() else {
+        return;
+    };
+    commands.insert_resource(Draining {
+        deadline: time.elapsed() + *timeout,
+        pending: connections.iter().collect(),
+        failed: Vec::new(),
+    });
+}
+
+pub fn track_drain(
+    mut commands: Commands,
+    mut draining: ResMut<Draining>,
+    connections: Query<&Outbox, With<WebSocketClient>>,
+    time: Res<Time>,
+    mut ev_drained: EventWriter<ConnectionsDrained>,
+) {
+    let Draining {
+        pending, failed, ..
+    } = &mut *draining;
+    pending.retain(|&entity| match connections.get(entity) {
+        Ok(outbox) => !outbox.queue.is_empty(),
+        Err(_) => {
+            // the connection went away before everything was sent
+            failed.push(entity);
+            false
+        }
+    });
+    if time.elapsed() >= draining.deadline {
+        let Draining {
+            pending, failed, ..
+        } = &mut *draining;
+        failed.extend(pending.drain());
+    }
+    if draining.pending.is_empty() {
+        ev_drained.send(ConnectionsDrained {
+            failed: std::mem::take(&mut draining.failed),
+        });
+        commands.remove_resource::<Draining>();
+    }
+}
+
+pub fn log_drained(mut ev_drained: EventReader<ConnectionsDrained>) {
+    for ConnectionsDrained { failed } in ev_drained.read() {
+        match failed.is_empty() {
+            true => info!("All connections drained"),
+            false => warn!("Connections that failed to drain: {failed:?}"),
+        }
+    }
+}
+
+/// Split outgoing messages into websocket frames of at most this many bytes, `None` sends
+/// each message as one frame. Some intermediaries cope better with many small frames.
+///
+/// Native only, browsers decide on framing themselves. Receivers reassemble the frames.
+#[derive(Resource, Default)]
+pub struct MaxOutFrameSize(pub Option<usize>);
+
+/// Encode, diff and compress everything as usual, but never write it to a socket. Each
+/// message is reported as [`SendOutcome::DryRun`] with its size instead, e.g. to profile
+/// the encoding or size the bandwidth before going live. Off by default.
+#[derive(Resource, Default)]
+pub struct DryRun(pub bool);
+
+/// Stop handing messages to the browser while its socket still has this many bytes to send,
+/// they wait in the [`Outbox`] instead, like they do natively when the socket would block.
+/// Without it the browser buffers everything, however slow the connection.
+///
+/// Wasm only, natively the socket's own buffer pushes back.
+#[derive(Resource)]
+pub struct MaxBufferedAmount(pub usize);
+
+impl Default for MaxBufferedAmount {
+    fn default() -> Self {
+        Self(1 << 20)
+    }
+}
+
+/// Bytes the browser took for a connection but hasn't sent yet, as of the last
+/// [`flush_outbox`]. Wasm only, see [`MaxBufferedAmount`].
+#[derive(Component, PartialEq, Eq)]
+pub struct BufferedAmount(usize);
+
+pub fn track_buffered_amount(
+    mut commands: Commands,
+    mut q: Query<(Entity, &WebSocketClient, Option<&mut BufferedAmount>)>,
+) {
+    for (entity, client, amount) in &mut q {
+        let Some(bytes) = client.buffered_bytes() else {
+            continue;
+        };
+        match amount {
+            Some(mut amount) => {
+                amount.set_if_neq(BufferedAmount(bytes));
+            }
+            None => {
+                commands.entity(entity).insert(BufferedAmount(bytes));
+            }
+        }
+    }
+}
+
+/// What happened to a queued message when [`flush_outbox`] tried to send it
+#[derive(Clone, Debug)]
+pub enum SendOutcome {
+    /// Handed to the websocket
+    Sent,
+    /// Taken by tungstenite but the socket would block, or by the browser on top of more than
+    /// [`MaxBufferedAmount`], so it goes out after what's already waiting
+    Queued,
+    /// tungstenite's write buffer is full, or the browser's socket isn't open yet or holds more
+    /// than [`MaxBufferedAmount`], the message stays in the [`Outbox`] for the next frame
+    WouldBlock,
+    /// Dropped
+    Failed(String),
+    /// Dropped without touching the socket because of [`DryRun`]
+    DryRun,
+}
+
+/// Reports every attempt [`flush_outbox`] makes at sending a message, e.g. for retries or metrics,
+/// and every [`SendTo`](crate::SendTo) rejected because of
+/// [`SendWhileClosed`](crate::SendWhileClosed)
+#[derive(Event, Clone)]
+pub struct MessageSendOutcome {
+    pub entity: Entity,
+    pub bytes: usize,
+    pub outcome: SendOutcome,
+}
+
+pub fn log_send_outcomes(mut ev_outcome: EventReader<MessageSendOutcome>) {
+    for MessageSendOutcome {
+        entity,
+        bytes,
+        outcome,
+    } in ev_outcome.read()
+    {
+        match outcome {
+            SendOutcome::Sent | SendOutcome::Queued => info!("Data successfully sent!"),
+            SendOutcome::WouldBlock => debug!("{entity} can't take {bytes} more bytes this frame"),
+            SendOutcome::Failed(e) => warn!("Could not send {bytes} bytes to {entity}: {e}"),
+            SendOutcome::DryRun => debug!("Dry run, not sending {bytes} bytes to {entity}"),
+        }
+    }
+}
+
+/// Write `msg`, as one frame or in fragments of at most `max_frame_size` bytes
+#[cfg(not(target_arch = "wasm32"))]
+fn write_queued(
+    socket: &mut WebSocket<MaybeTlsStream<TcpStream>>,
+    msg: &mut QueuedMessage,
+    max_frame_size: Option<usize>,
+) -> SendOutcome {
+    let len = msg.data.len();
+    let Some(max_frame_size) = max_frame_size.filter(|&max| max > 0 && len > max) else {
+        let data = std::mem::take(&mut msg.data);
+        let message = match msg.text {
+            // only `push_text` sets it, from a `String`
+            true => Message::Text(String::from_utf8(data).unwrap()),
+            false => Message::Binary(data),
+        };
+        return match socket.write(message) {
+            Ok(_) => SendOutcome::Sent,
+            Err(tungstenite::Error::WriteBufferFull(Message::Frame(frame))) => {
+                // tungstenite hands the message back to us
+                msg.data = frame.into_data();
+                SendOutcome::WouldBlock
+            }
+            Err(tungstenite::Error::Io(e)) if e.kind() == ErrorKind::WouldBlock => {
+                SendOutcome::Queued
+            }
+            Err(e) => SendOutcome::Failed(e.to_string()),
+        };
+    };
+    let mut outcome = SendOutcome::Sent;
+    while msg.sent < len {
+        let end = (msg.sent + max_frame_size).min(len);
+        let opcode = match msg.sent {
+            0 if msg.text => OpCode::Data(Data::Text),
+            0 => OpCode::Data(Data::Binary),
+            _ => OpCode::Data(Data::Continue),
+        };
+        let frame = Frame::message(msg.data[msg.sent..end].to_vec(), opcode, end == len);
+        match socket.write(Message::Frame(frame)) {
+            Ok(_) => {}
+            Err(tungstenite::Error::Io(e)) if e.kind() == ErrorKind::WouldBlock => {
+                outcome = SendOutcome::Queued;
+            }
+            Err(tungstenite::Error::WriteBufferFull(_)) => return SendOutcome::WouldBlock,
+            Err(e) => return SendOutcome::Failed(e.to_string()),
+        }
+        msg.sent = end;
+    }
+    outcome
+}
+
+/// Hand a message to the browser with `send`, unless its socket isn't open yet or still has
+/// more than [`MaxBufferedAmount`] to send, going by `buffered`
+#[cfg(any(target_arch = "wasm32", test))]
+fn browser_send(
+    open: bool,
+    buffered: impl Fn() -> usize,
+    max_buffered: usize,
+    send: impl FnOnce() -> Result<(), String>,
+) -> SendOutcome {
+    // the browser throws while still connecting, so it waits in the outbox until it's open
+    if !open || buffered() > max_buffered {
+        return SendOutcome::WouldBlock;
+    }
+    match send() {
+        Ok(()) if buffered() > max_buffered => SendOutcome::Queued,
+        Ok(()) => SendOutcome::Sent,
+        Err(e) => SendOutcome::Failed(e),
+    }
+}
+
+/// Try to write the next queued message of one connection, returning what happened to it and
+/// its size, or `None` if nothing is queued
+#[allow(unused_variables)] // `max_frame_size` is native only, `max_buffered` wasm only
+pub(crate) fn write_next(
+    client: &mut WebSocketClient,
+    outbox: &mut Outbox,
+    budget: &mut OutboxMemoryBudget,
+    max_frame_size: Option<usize>,
+    max_buffered: usize,
+    dry_run: bool,
+) -> Option<(SendOutcome, usize)> {
+    let msg = outbox.queue.pop_front()?;
+    let len = msg.data.len();
+    #[cfg(target_arch = "wasm32")]
+    let outcome = if dry_run {
+        SendOutcome::DryRun
+    } else {
+        let socket = &client.0.socket;
+        let buffered = || socket.buffered_amount() as usize;
+        browser_send(client.0.is_open(), buffered, max_buffered, || {
+            let sent = match msg.text {
+                // only `push_text` sets it, from a `String`
+                true => socket.send_with_str(std::str::from_utf8(&msg.data).unwrap()),
+                false => socket.send_with_u8_array(msg.data.as_slice()),
+            };
+            sent.map_err(|e| format!("{e:?}"))
+        })
+    };
+    #[cfg(not(target_arch = "wasm32"))]
+    let mut msg = msg;
+    #[cfg(not(target_arch = "wasm32"))]
+    let outcome = if dry_run {
+        SendOutcome::DryRun
+    } else {
+        write_queued(&mut client.socket(), &mut msg, max_frame_size)
+    };
+    match &outcome {
+        SendOutcome::Sent | SendOutcome::Queued | SendOutcome::DryRun => msg.resolve(Ok(())),
+        SendOutcome::WouldBlock => {
+            // keep the rest for the next frame
+            outbox.queue.push_front(msg);
+            return Some((outcome, len));
+        }
+        SendOutcome::Failed(e) => msg.resolve(Err(FlushError::Send(e.clone()))),
+    }
+    outbox.release(len, budget);
+    Some((outcome, len))
+}
+
+/// Bytes sent and received over all connections, e.g. for servers on metered links
+#[derive(Resource, Default)]
+pub struct Bandwidth {
+    /// Throttle sending to this many bytes per second in total, shared fairly between connections
+    pub cap: Option<usize>,
+    pub sent_per_second: usize,
+    pub received_per_second: usize,
+    /// Messages sent and received together
+    pub messages_per_second: usize,
+    sent: usize,
+    pub(crate) received: usize,
+    pub(crate) messages: usize,
+    /// [`Time::elapsed`] when the current second of accounting started
+    window_start: Duration,
+    /// Which connection gets to send first next frame, so none is starved by the cap
+    next_first: usize,
+}
+
+impl Bandwidth {
+    fn can_send(&self) -> bool {
+        self.cap.is_none_or(|cap| self.sent < cap)
+    }
+}
+
+pub fn roll_bandwidth_window(mut bandwidth: ResMut<Bandwidth>, time: Res<Time>) {
+    let window = time.elapsed().saturating_sub(bandwidth.window_start);
+    if window >= Duration::from_secs(1) {
+        // frames rarely end exactly on the second, so scale by how long the window really was
+        let per_second = |count: usize| (count as f64 / window.as_secs_f64()) as usize;
+        bandwidth.sent_per_second = per_second(std::mem::take(&mut bandwidth.sent));
+        bandwidth.received_per_second = per_second(std::mem::take(&mut bandwidth.received));
+        bandwidth.messages_per_second = per_second(std::mem::take(&mut bandwidth.messages));
+        bandwidth.window_start = time.elapsed();
+    }
+}
+
+/// Drop received frames that are byte for byte one we sent recently, e.g. everything coming
+/// back from an echo server, so local state isn't applied a second time. Heartbeats always
+/// pass. Opt-in per connection.
+///
+/// Matching is by content hash, so a genuine frame identical to one we just sent is dropped too.
+#[derive(Component)]
+pub struct LoopbackFilter {
+    /// Hashes of the last `capacity` sent frames, oldest first
+    recent: VecDeque<u64>,
+    capacity: usize,
+}
+
+impl Default for LoopbackFilter {
+    fn default() -> Self {
+        Self {
+            recent: VecDeque::new(),
+            capacity: 64,
+        }
+    }
+}
+
+impl LoopbackFilter {
+    fn record(&mut self, hash: u64) {
+        if self.recent.len() >= self.capacity {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(hash);
+    }
+
+    /// Whether a frame is an echo of one we sent, each sent frame only filters one echo
+    pub(crate) fn take_echo(&mut self, hash: u64) -> bool {
+        match self.recent.iter().position(|&recent| recent == hash) {
+            Some(index) => {
+                self.recent.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+pub(crate) fn frame_hash(frame: &[u8]) -> u64 {
+    let mut hasher = std::hash::DefaultHasher::new();
+    frame.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Application level flow control: the peer grants us a window of bytes, and once that's
+/// used up we stop sending until it grants more with a credit frame. Unlike the socket's
+/// `WouldBlock` this holds across every buffer in between, so a slow peer throttles us end
+/// to end.
+///
+/// Both peers need this component with the same `window`, each starts out granting all of it
+/// to the other, again on every reconnect. Heartbeats and credit frames don't count.
+#[derive(Component)]
+pub struct FlowControl {
+    /// Bytes we let the peer have in flight
+    window: usize,
+    /// Bytes the peer still lets us send
+    pub(crate) credit: usize,
+    /// Bytes received since we last granted the peer more credit
+    pub(crate) received: usize,
+}
+
+impl FlowControl {
+    pub fn new(window: usize) -> Self {
+        Self {
+            window,
+            credit: window,
+            received: 0,
+        }
+    }
+
+    /// Whether `msg` has to wait for more credit. A message bigger than the whole window
+    /// still goes out once the peer granted all of it, so it can't get stuck.
+    fn withholds(&self, msg: &QueuedMessage) -> bool {
+        msg.sent == 0 && msg.data.len() > self.credit && self.credit < self.window
+    }
+}
+
+/// Credit frames are tagged like this, followed by the big endian number of bytes granted
+pub const FLOW_CREDIT_TAG: [u8; 4] = [0xff, b'c', b'r', b'd'];
+
+fn credit_frame(bytes: u64) -> Vec<u8> {
+    [&FLOW_CREDIT_TAG[..], &bytes.to_be_bytes()].concat()
+}
+
+pub(crate) fn parse_credit_frame(frame: &[u8]) -> Option<u64> {
+    let (tag, bytes) = frame.split_first_chunk::<4>()?;
+    if *tag != FLOW_CREDIT_TAG {
+        return None;
+    }
+    Some(u64::from_be_bytes(bytes.try_into().ok()?))
+}
+
+/// Frames [`FlowControl`] lets through regardless of the window
+fn is_control_frame(frame: &[u8]) -> bool {
+    parse_heartbeat(frame).is_some() || parse_credit_frame(frame).is_some()
+}
+
+/// Grant the peer more credit once it used up half its window, so it rarely has to wait
+pub fn grant_flow_credit(
+    mut q: Query<(&mut FlowControl, &mut Outbox, Ref<WebSocketClient>)>,
+    mut budget: ResMut<OutboxMemoryBudget>,
+) {
+    for (mut flow, mut outbox, client) in &mut q {
+        if client.is_added() {
+            // a fresh connection, whatever was in flight on the old one is gone
+            flow.credit = flow.window;
+            flow.received = 0;
+        }
+        if flow.received < flow.window / 2 {
+            continue;
+        }
+        if outbox
+            .push(credit_frame(flow.received as u64), &mut budget)
+            .is_err()
+        {
+            continue;
+        }
+        flow.received = 0;
+        // ahead of data that may be waiting for credit itself, but not into a fragmented message
+        let credit = outbox.queue.pop_back().unwrap();
+        let at = outbox
+            .queue
+            .front()
+            .map_or(0, |msg| usize::from(msg.sent > 0));
+        outbox.queue.insert(at, credit);
+    }
+}
+
+#[allow(clippy::type_complexity, clippy::too_many_arguments)]
+pub fn flush_outbox(
+    mut q: Query<
+        (
+            Entity,
+            &mut WebSocketClient,
+            &mut Outbox,
+            Option<&mut IoActivity>,
+            Option<&mut LoopbackFilter>,
+            Option<&mut FlowControl>,
+            Option<&mut NetworkStats>,
+        ),
+        Without<Closing>,
+    >,
+    mut budget: ResMut<OutboxMemoryBudget>,
+    max_frame_size: Res<MaxOutFrameSize>,
+    max_buffered: Res<MaxBufferedAmount>,
+    dry_run: Res<DryRun>,
+    mut bandwidth: ResMut<Bandwidth>,
+    time: Res<Time>,
+    mut ev_outcome: EventWriter<MessageSendOutcome>,
+) {
+    let mut connections = q.iter_mut().collect::<Vec<_>>();
+    if connections.is_empty() {
+        return;
+    }
+    let first = bandwidth.next_first % connections.len();
+    connections.rotate_left(first);
+    bandwidth.next_first = first + 1;
+
+    // one message per connection and round, so a capped budget is shared round-robin
+    let mut stalled = vec![false; connections.len()];
+    'rounds: while stalled.contains(&false) {
+        for ((entity, client, outbox, activity, loopback, flow, stats), stalled) in
+            connections.iter_mut().zip(&mut stalled)
+        {
+            if *stalled {
+                continue;
+            }
+            if !bandwidth.can_send() {
+                break 'rounds;
+            }
+            // the whole message is still there, even if some of its fragments went out already
+            let hash = loopback
+                .as_ref()
+                .and_then(|_| outbox.queue.front())
+                .filter(|msg| parse_heartbeat(&msg.data).is_none())
+                .map(|msg| frame_hash(&msg.data));
+            let control = outbox
+                .queue
+                .front()
+                .is_some_and(|msg| is_control_frame(&msg.data));
+            if let (Some(flow), Some(msg)) = (flow.as_deref(), outbox.queue.front()) {
+                if !control && flow.withholds(msg) {
+                    *stalled = true;
+                    continue;
+                }
+            }
+            let Some((outcome, len)) = write_next(
+                client,
+                outbox,
+                &mut budget,
+                max_frame_size.0,
+                max_buffered.0,
+                dry_run.0,
+            ) else {
+                *stalled = true;
+                continue;
+            };
+            match outcome {
+                SendOutcome::Sent | SendOutcome::Queued => {
+                    if let (Some(loopback), Some(hash)) = (loopback.as_deref_mut(), hash) {
+                        loopback.record(hash);
+                    }
+                    bandwidth.sent += len;
+                    bandwidth.messages += 1;
+                    if let Some(stats) = stats.as_deref_mut() {
+                        stats.bytes_sent += len as u64;
+                        stats.messages_sent += 1;
+                    }
+                    if let Some(flow) = flow.as_deref_mut().filter(|_| !control) {
+                        flow.credit = flow.credit.saturating_sub(len);
+                    }
+                    if let Some(activity) = activity.as_deref_mut() {
+                        activity.last = time.elapsed();
+                    }
+                }
+                // the connection can't take any more this frame
+                SendOutcome::WouldBlock | SendOutcome::Failed(_) => *stalled = true,
+                // nothing went over the wire, so it doesn't count against the cap
+                SendOutcome::DryRun => {}
+            }
+            ev_outcome.send(MessageSendOutcome {
+                entity: *entity,
+                bytes: len,
+                outcome,
+            });
+        }
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    for (_, client, ..) in &mut connections {
+        match client.socket().flush() {
+            Ok(_) => {}
+            Err(tungstenite::Error::Io(e)) if e.kind() == ErrorKind::WouldBlock => { /* ignore */ }
+            Err(e) => warn!("Could not flush the connection: {e:?}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use bevy::{time::TimeUpdateStrategy, utils::HashMap};
+
+    use super::*;
+    use crate::{
+        testing::{
+            app, connect, connected_app, drain, echo, noise, read_raw_frame, send_outcome,
+            update_until,
+        },
+        Compression, ConnectionOpened, SendTo, WebSocketConnectionEvents, WebSocketMessageReceived,
+    };
+
+    #[test]
+    fn send_outcomes() {
+        let (mut app, connection) = connected_app();
+        assert!(matches!(
+            send_outcome(&mut app, connection),
+            SendOutcome::Sent
+        ));
+        app.insert_resource(DryRun(true));
+        assert!(matches!(
+            send_outcome(&mut app, connection),
+            SendOutcome::DryRun
+        ));
+    }
+
+    #[test]
+    fn browser_send_outcomes() {
+        let sent = || Ok(());
+        let closed = browser_send(false, || 0, 10, sent);
+        assert!(matches!(closed, SendOutcome::WouldBlock));
+        let backed_up = browser_send(true, || 11, 10, || panic!("sent while held back"));
+        assert!(matches!(backed_up, SendOutcome::WouldBlock));
+        assert!(matches!(
+            browser_send(true, || 10, 10, sent),
+            SendOutcome::Sent
+        ));
+
+        let buffered = std::cell::Cell::new(0);
+        let send = || {
+            buffered.set(20);
+            Ok(())
+        };
+        let queued = browser_send(true, || buffered.get(), 10, send);
+        assert!(matches!(queued, SendOutcome::Queued));
+
+        let failed = browser_send(true, || 0, 10, || Err("closed".to_owned()));
+        assert!(matches!(failed, SendOutcome::Failed(e) if e == "closed"));
+    }
+
+    #[test]
+    fn outboxes_shed_messages_over_the_budget() {
+        let mut app = app(Duration::from_millis(10));
+        app.insert_resource(OutboxMemoryBudget::new(10));
+        let first = app.world_mut().spawn(Outbox::default()).id();
+        let second = app.world_mut().spawn(Outbox::default()).id();
+        let send = |app: &mut App, entity| {
+            app.world_mut().send_event(SendTo {
+                entity,
+                data: vec![0; 6],
+            });
+            app.update();
+            drain::<OutboxBudgetExceeded>(app)
+        };
+        assert!(send(&mut app, first).is_empty());
+        let refused = send(&mut app, second);
+        assert_eq!(refused.len(), 1);
+        assert_eq!((refused[0].entity, refused[0].refused_bytes), (second, 6));
+        assert_eq!(
+            app.world().get::<Outbox>(second).unwrap().pending_count(),
+            0
+        );
+
+        // a connection going away gives its share back
+        app.world_mut().despawn(first);
+        assert!(send(&mut app, second).is_empty());
+        assert_eq!(app.world().resource::<OutboxMemoryBudget>().used, 6);
+    }
+
+    /// Queue `msg` on `entity`'s outbox, returning its flush signal
+    fn push_with_ack(
+        app: &mut App,
+        entity: Entity,
+        msg: &[u8],
+    ) -> mpsc::Receiver<Result<(), FlushError>> {
+        app.world_mut()
+            .resource_scope(|world, mut budget: Mut<OutboxMemoryBudget>| {
+                let mut outbox = world.get_mut::<Outbox>(entity).unwrap();
+                outbox.push_with_ack(msg.to_vec(), &mut budget).unwrap()
+            })
+    }
+
+    #[test]
+    fn flush_signals_resolve_once_the_message_is_written() {
+        let (mut app, connection) = connected_app();
+        let flushed = push_with_ack(&mut app, connection, b"ack me");
+        assert!(
+            flushed.try_recv().is_err(),
+            "resolved before it was written"
+        );
+        let result = update_until(&mut app, |_| flushed.try_recv().ok());
+        assert!(result.is_ok());
+        echo(&mut app, connection, b"ack me");
+
+        // messages that never make it out say so
+        let offline = app.world_mut().spawn(Outbox::default()).id();
+        let flushed = push_with_ack(&mut app, offline, b"nobody listens");
+        app.world_mut().despawn(offline);
+        assert!(matches!(
+            flushed.try_recv(),
+            Ok(Err(FlushError::ConnectionClosed))
+        ));
+    }
+
+    #[test]
+    fn large_messages_go_out_in_frames_of_the_maximum_size() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}/", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            // we only send once the connection opened, so it can't have buffered any frames
+            let _socket = tungstenite::accept(stream.try_clone().unwrap()).unwrap();
+            let mut frames = Vec::new();
+            loop {
+                let frame = read_raw_frame(&mut stream).unwrap();
+                let fin = frame.0;
+                frames.push(frame);
+                if fin {
+                    return frames;
+                }
+            }
+        });
+        let mut app = app(Duration::from_millis(10));
+        app.insert_resource(MaxOutFrameSize(Some(1000)));
+        app.world_mut()
+            .send_event(WebSocketConnectionEvents::SetupConnection(Some(url)));
+        let connection = update_until(&mut app, |app| drain::<ConnectionOpened>(app).pop()).entity;
+        let msg = noise(2500);
+        app.world_mut().send_event(SendTo {
+            entity: connection,
+            data: msg.clone(),
+        });
+        update_until(&mut app, |_| server.is_finished().then_some(()));
+        let frames = server.join().unwrap();
+        let layout: Vec<_> = frames
+            .iter()
+            .map(|(fin, opcode, payload)| (*fin, *opcode, payload.len()))
+            .collect();
+        // binary, then continuations
+        assert_eq!(layout, [(false, 2, 1000), (false, 0, 1000), (true, 0, 500)]);
+        let reassembled: Vec<u8> = frames.into_iter().flat_map(|frame| frame.2).collect();
+        assert_eq!(reassembled, msg);
+    }
+
+    #[test]
+    fn a_global_bandwidth_cap_is_shared_between_connections() {
+        let (mut app, first) = connected_app();
+        let connections = [first, connect(&mut app), connect(&mut app)];
+        app.insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_millis(
+            100,
+        )));
+        let now = app.world().resource::<Time>().elapsed();
+        app.insert_resource(Bandwidth {
+            cap: Some(1000),
+            window_start: now,
+            ..default()
+        });
+        for entity in connections {
+            for _ in 0..10 {
+                app.world_mut().send_event(SendTo {
+                    entity,
+                    data: vec![0; 100],
+                });
+            }
+        }
+        let mut sent = HashMap::<Entity, usize>::new();
+        let count = |app: &mut App, sent: &mut HashMap<Entity, usize>| {
+            for outcome in drain::<MessageSendOutcome>(app) {
+                if matches!(outcome.outcome, SendOutcome::Sent) && outcome.bytes == 100 {
+                    *sent.entry(outcome.entity).or_default() += outcome.bytes;
+                }
+            }
+        };
+        // still within the first second
+        for _ in 0..9 {
+            app.update();
+            count(&mut app, &mut sent);
+        }
+        assert_eq!(sent.values().sum::<usize>(), 1000, "{sent:?}");
+        for entity in connections {
+            assert!((300..=400).contains(&sent[&entity]), "{sent:?}");
+        }
+        update_until(&mut app, |app| {
+            count(app, &mut sent);
+            (sent.values().sum::<usize>() == 3000).then_some(())
+        });
+    }
+
+    #[test]
+    fn draining_waits_for_every_outbox_or_reports_the_stragglers() {
+        let (mut app, first) = connected_app();
+        let connections = [first, connect(&mut app), connect(&mut app)];
+        let queue = |app: &mut App| {
+            for entity in connections {
+                for _ in 0..10 {
+                    app.world_mut().send_event(SendTo {
+                        entity,
+                        data: vec![0; 100],
+                    });
+                }
+            }
+        };
+        queue(&mut app);
+        app.world_mut().send_event(DrainAll {
+            timeout: Duration::from_secs(5),
+        });
+        let drained = update_until(&mut app, |app| drain::<ConnectionsDrained>(app).pop());
+        assert!(drained.failed.is_empty(), "{drained:?}");
+        for entity in connections {
+            assert!(app.world().get::<Outbox>(entity).unwrap().queue.is_empty());
+        }
+
+        // 3000 bytes take three seconds at this cap
+        app.insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_millis(
+            100,
+        )));
+        let now = app.world().resource::<Time>().elapsed();
+        app.insert_resource(Bandwidth {
+            cap: Some(1000),
+            window_start: now,
+            ..default()
+        });
+        queue(&mut app);
+        app.world_mut().send_event(DrainAll {
+            timeout: Duration::from_millis(500),
+        });
+        let drained = update_until(&mut app, |app| drain::<ConnectionsDrained>(app).pop());
+        assert!(!drained.failed.is_empty());
+        assert!(drained
+            .failed
+            .iter()
+            .all(|entity| connections.contains(entity)));
+        assert!(app.world().get_resource::<Draining>().is_none());
+    }
+
+    #[test]
+    fn loopback_filters_drop_echoes_but_not_remote_frames() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}/", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut socket = tungstenite::accept(stream).unwrap();
+            let echo = loop {
+                if let msg @ Message::Binary(_) = socket.read().unwrap() {
+                    break msg;
+                }
+            };
+            socket.send(echo.clone()).unwrap();
+            socket.send(Message::binary(b"theirs".to_vec())).unwrap();
+            // one sent frame only filters one echo
+            socket.send(echo).unwrap();
+            while socket.read().is_ok() {}
+        });
+        let mut app = app(Duration::from_millis(10));
+        app.world_mut()
+            .send_event(WebSocketConnectionEvents::SetupConnection(Some(url)));
+        let connection = update_until(&mut app, |app| drain::<ConnectionOpened>(app).pop()).entity;
+        app.world_mut()
+            .entity_mut(connection)
+            .insert(LoopbackFilter::default());
+        app.world_mut().send_event(SendTo {
+            entity: connection,
+            data: b"mine".to_vec(),
+        });
+        let mut received = Vec::new();
+        update_until(&mut app, |app| {
+            received.extend(
+                drain::<WebSocketMessageReceived>(app)
+                    .into_iter()
+                    .map(|msg| msg.data),
+            );
+            (received.len() == 2).then_some(())
+        });
+        assert_eq!(received, [b"theirs".to_vec(), b"mine".to_vec()]);
+        drop(app);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn dry_runs_report_sizes_without_writing() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}/", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let _socket = tungstenite::accept(stream.try_clone().unwrap()).unwrap();
+            std::iter::from_fn(|| read_raw_frame(&mut stream))
+                .find(|(_, opcode, _)| *opcode == 2)
+                .map(|(_, _, payload)| payload)
+        });
+        let mut app = app(Duration::from_millis(10));
+        app.insert_resource(DryRun(true));
+        app.world_mut()
+            .send_event(WebSocketConnectionEvents::SetupConnection(Some(url)));
+        let connection = update_until(&mut app, |app| drain::<ConnectionOpened>(app).pop()).entity;
+        app.world_mut()
+            .entity_mut(connection)
+            .insert(Compression::default());
+        app.world_mut().send_event(SendTo {
+            entity: connection,
+            data: vec![0; 4096],
+        });
+        let outcome = update_until(&mut app, |app| drain::<MessageSendOutcome>(app).pop());
+        assert!(matches!(outcome.outcome, SendOutcome::DryRun));
+        // the size after compression, what would have gone out
+        assert!(
+            outcome.bytes > 0 && outcome.bytes < 4096,
+            "{}",
+            outcome.bytes
+        );
+
+        app.insert_resource(DryRun(false));
+        app.world_mut().send_event(SendTo {
+            entity: connection,
+            data: b"for real".to_vec(),
+        });
+        update_until(&mut app, |_| server.is_finished().then_some(()));
+        let written = server.join().unwrap().expect("no binary frame");
+        // only the message after the dry run reached the socket
+        assert_eq!(
+            Compression::default().decode(&written),
+            Some(b"for real".to_vec())
+        );
+    }
+
+    #[test]
+    fn a_slow_receivers_window_throttles_the_sender() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}/", listener.local_addr().unwrap());
+        // a receiver with a 100 byte window that only grants credit when told to
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_millis(300)))
+                .unwrap();
+            let mut socket = tungstenite::accept(stream).unwrap();
+            let burst = |socket: &mut WebSocket<TcpStream>| {
+                let mut received = 0;
+                loop {
+                    match socket.read() {
+                        Ok(Message::Binary(_)) => received += 1,
+                        Ok(_) => {}
+                        // nothing more came within the timeout
+                        Err(_) => return received,
+                    }
+                }
+            };
+            let first = burst(&mut socket);
+            socket.send(Message::binary(credit_frame(80))).unwrap();
+            let second = burst(&mut socket);
+            (first, second)
+        });
+        let mut app = app(Duration::from_millis(10));
+        app.world_mut()
+            .send_event(WebSocketConnectionEvents::SetupConnection(Some(url)));
+        let connection = update_until(&mut app, |app| drain::<ConnectionOpened>(app).pop()).entity;
+        app.world_mut()
+            .entity_mut(connection)
+            .insert(FlowControl::new(100));
+        for _ in 0..5 {
+            app.world_mut().send_event(SendTo {
+                entity: connection,
+                data: vec![0; 40],
+            });
+        }
+        update_until(&mut app, |_| server.is_finished().then_some(()));
+        // 80 of the 100 bytes, then 80 more once the receiver grants them
+        assert_eq!(server.join().unwrap(), (2, 2));
+        let outbox = app.world().get::<Outbox>(connection).unwrap();
+        assert_eq!(outbox.queue.len(), 1);
+    }
+
+    #[test]
+    fn pending_counts_track_enqueues_and_flushes() {
+        let (mut app, connection) = connected_app();
+        let pending = |app: &App| {
+            let outbox = app.world().get::<Outbox>(connection).unwrap();
+            (outbox.pending_count(), outbox.pending_bytes())
+        };
+        assert_eq!(pending(&app), (0, 0));
+        app.world_mut()
+            .resource_scope(|world, mut budget: Mut<OutboxMemoryBudget>| {
+                let mut outbox = world.get_mut::<Outbox>(connection).unwrap();
+                for len in [10, 20, 30] {
+                    outbox.push(vec![0; len], &mut budget).unwrap();
+                }
+            });
+        assert_eq!(pending(&app), (3, 60));
+        update_until(&mut app, |app| (pending(app) == (0, 0)).then_some(()));
+        let client = app.world().get::<WebSocketClient>(connection).unwrap();
+        // tungstenite doesn't say what it still buffers
+        assert_eq!(client.buffered_bytes(), None);
+    }
+}
@@ -0,0 +1,723 @@
+//! Reading incoming messages off the connections and handing them to the app.
+
+use std::collections::VecDeque;
+
+use bevy::{prelude::*, utils::HashMap};
+
+use crate::{
+    frame_hash, parse_credit_frame, parse_heartbeat, Bandwidth, BinaryDiff, Compression,
+    FlowControl, HeartbeatReceived, InjectInboundMessage, IoActivity, LoopbackFilter,
+    MessagesDropped, Multiplexed, NetworkStats, Outbox, ReceiveMode, ReceivedMessage,
+    WebSocketClient, WebSocketMessageBatch, WebSocketMessageReceived, WireStage, WireTap,
+};
+
+#[cfg(not(target_arch = "wasm32"))]
+use crate::{
+    parse_ping_payload, CloseCode, ConnectionClosed, ConnectionState, Disconnect, HeartbeatKind,
+    NativeSocket, ResendQueuedOnReconnect, StrictProtocol,
+};
+#[cfg(not(target_arch = "wasm32"))]
+use std::{
+    io::ErrorKind,
+    sync::{mpsc, Arc, Mutex, PoisonError, Weak},
+    time::Duration,
+};
+#[cfg(not(target_arch = "wasm32"))]
+use tungstenite::Message;
+
+/// Which frames to give up on when the wasm receive queue is full, see [`RecvQueueLimit`]
+#[cfg(any(target_arch = "wasm32", test))]
+#[derive(Clone, Copy)]
+pub enum RecvOverflowPolicy {
+    /// Make room by dropping the frame that has waited longest
+    DropOldest,
+    /// Drop the frame that just arrived
+    DropNewest,
+}
+
+#[cfg(any(target_arch = "wasm32", test))]
+impl RecvOverflowPolicy {
+    /// Queue `frame` behind at most `max_depth` frames in total, returning whether one was
+    /// dropped to keep to that
+    pub(crate) fn push<T>(self, queue: &mut VecDeque<T>, frame: T, max_depth: usize) -> bool {
+        if queue.len() < max_depth {
+            queue.push_back(frame);
+            return false;
+        }
+        if let RecvOverflowPolicy::DropOldest = self {
+            queue.pop_front();
+            queue.push_back(frame);
+        }
+        true
+    }
+}
+
+/// A zeroed buffer for a received message of `len` bytes, `None` if it's too big for
+/// `max_size` or the allocation fails, instead of aborting on out of memory
+#[cfg(any(target_arch = "wasm32", test))]
+pub(crate) fn message_buffer(len: usize, max_size: usize) -> Option<Vec<u8>> {
+    if len > max_size {
+        warn!("Dropping a {len} byte message, the limit is {max_size}");
+        return None;
+    }
+    let mut data = Vec::new();
+    if data.try_reserve_exact(len).is_err() {
+        warn!("Dropping a {len} byte message, out of memory");
+        return None;
+    }
+    data.resize(len, 0);
+    Some(data)
+}
+
+/// The UTF-8 bytes of a received text message, `None` if they're too many for `max_size`
+#[cfg(any(target_arch = "wasm32", test))]
+pub(crate) fn text_message(text: String, max_size: usize) -> Option<Vec<u8>> {
+    let len = text.len();
+    if len > max_size {
+        warn!("Dropping a {len} byte text message, the limit is {max_size}");
+        return None;
+    }
+    Some(text.into_bytes())
+}
+
+/// Read native connections on a thread of their own instead of once per frame, so messages come off
+/// the socket as they arrive however slow the frame rate, and [`recv_info`] hands over all of them
+/// on the next frame. Up to `capacity` messages wait there; past that the thread stops reading
+/// until they're taken, leaving the peer to TCP flow control. The socket stays nonblocking so sends
+/// never wait for a read, the thread sleeps instead when there's nothing to read. These are plain
+/// threads rather than tasks, a read loop would keep an
+/// [`AsyncComputeTaskPool`](bevy::tasks::AsyncComputeTaskPool) thread busy for as long as the
+/// connection is open.
+///
+/// Polling costs a wakeup and a lock of the socket per connection every `idle`, and sends to
+/// that connection wait for the lock while a read holds it. So the sleep doubles while the
+/// connection stays quiet, up to `max_idle`, and drops back to `idle` once something arrives:
+/// busy connections are read within a millisecond, quiet ones wake no more than once a frame
+/// at 60 fps.
+///
+/// On by default. Without the resource, connections opened from then on are read once per
+/// frame in [`recv_info`] instead, which ties receiving to the frame rate.
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Resource, Clone, Copy, Debug)]
+pub struct ReadThread {
+    pub capacity: usize,
+    pub idle: Duration,
+    pub max_idle: Duration,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Default for ReadThread {
+    fn default() -> Self {
+        Self {
+            capacity: 1024,
+            idle: Duration::from_millis(1),
+            max_idle: Duration::from_millis(16),
+        }
+    }
+}
+
+/// Where a connection's [`ReadThread`] puts what it read
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Component)]
+pub struct ReadThreadReceiver {
+    /// The socket it reads, so a reconnect doesn't pick up what the old one left behind
+    socket: Weak<Mutex<NativeSocket>>,
+    received: Mutex<mpsc::Receiver<tungstenite::Result<Message>>>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl ReadThreadReceiver {
+    /// Whether this is the thread reading `client`, and not one left over from before a
+    /// reconnect
+    pub fn reads(&self, client: &WebSocketClient) -> bool {
+        std::ptr::eq(self.socket.as_ptr(), Arc::as_ptr(&client.0 .0))
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn spawn_read_threads(
+    mut commands: Commands,
+    // a reconnect may replace the client in place, which only counts as a change
+    changed: Query<
+        (Entity, &WebSocketClient, Option<&ReadThreadReceiver>),
+        Changed<WebSocketClient>,
+    >,
+    config: Res<ReadThread>,
+) {
+    let new = changed
+        .iter()
+        .filter(|(_, client, receiver)| receiver.is_none_or(|receiver| !receiver.reads(client)));
+    for (entity, client, _) in new {
+        let socket = Arc::downgrade(&client.0 .0);
+        let (sender, received) = mpsc::sync_channel(config.capacity);
+        let thread = {
+            let socket = socket.clone();
+            let config = *config;
+            std::thread::Builder::new()
+                .name(format!("read {entity}"))
+                .spawn(move || read_socket(socket, sender, config))
+        };
+        match thread {
+            Ok(_) => {
+                commands.entity(entity).insert(ReadThreadReceiver {
+                    socket,
+                    received: Mutex::new(received),
+                });
+            }
+            Err(e) => {
+                warn!("Could not start a read thread for {entity}, reading it every frame: {e}")
+            }
+        }
+    }
+}
+
+/// Body of a [`ReadThread`], returns once the connection fails or is dropped
+#[cfg(not(target_arch = "wasm32"))]
+fn read_socket(
+    socket: Weak<Mutex<NativeSocket>>,
+    sender: mpsc::SyncSender<tungstenite::Result<Message>>,
+    config: ReadThread,
+) {
+    let mut idle = config.idle;
+    while let Some(shared) = socket.upgrade() {
+        let read = shared.lock().unwrap_or_else(PoisonError::into_inner).read();
+        // don't keep the connection alive while waiting for room below
+        drop(shared);
+        match read {
+            Err(tungstenite::Error::Io(e))
+                if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+            {
+                std::thread::sleep(idle);
+                idle = (idle * 2).min(config.max_idle.max(config.idle));
+            }
+            read => {
+                idle = config.idle;
+                let failed = read.is_err();
+                if sender.send(read).is_err() || failed {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[allow(clippy::type_complexity, clippy::too_many_arguments)]
+pub fn recv_info(
+    mut commands: Commands,
+    mut q: Query<
+        (
+            Entity,
+            Option<&WebSocketClient>,
+            Option<&mut BinaryDiff>,
+            Option<&Compression>,
+            Has<Multiplexed>,
+            Option<&mut IoActivity>,
+            Option<&mut LoopbackFilter>,
+            Option<&mut FlowControl>,
+            Option<&mut NetworkStats>,
+        ),
+        // connections that aren't connected yet or anymore only get injected messages
+        Or<(With<WebSocketClient>, With<Outbox>)>,
+    >,
+    time: Res<Time>,
+    mut ev_received: EventWriter<WebSocketMessageReceived>,
+    mut ev_batch: EventWriter<WebSocketMessageBatch>,
+    receive_mode: Res<ReceiveMode>,
+    mut ev_heartbeat: EventWriter<HeartbeatReceived>,
+    mut bandwidth: ResMut<Bandwidth>,
+    tap: Option<Res<WireTap>>,
+    #[cfg(not(target_arch = "wasm32"))] mut ev_closed: EventWriter<ConnectionClosed>,
+    #[cfg(not(target_arch = "wasm32"))] resend_queued: Res<ResendQueuedOnReconnect>,
+    #[cfg(not(target_arch = "wasm32"))] strict: Res<StrictProtocol>,
+    #[cfg(not(target_arch = "wasm32"))] mut ev_disconnect: EventWriter<Disconnect>,
+    #[cfg(target_arch = "wasm32")] mut ev_dropped: EventWriter<MessagesDropped>,
+    #[cfg(not(target_arch = "wasm32"))] readers: Query<&ReadThreadReceiver>,
+    #[cfg(debug_assertions)] mut ev_inject: EventReader<InjectInboundMessage>,
+) {
+    #[cfg(debug_assertions)]
+    let mut injected = HashMap::<Entity, Vec<Vec<u8>>>::new();
+    #[cfg(debug_assertions)]
+    for InjectInboundMessage { entity, data } in ev_inject.read() {
+        injected.entry(*entity).or_default().push(data.clone());
+    }
+    for (
+        entity,
+        client,
+        mut diff,
+        compression,
+        multiplexed,
+        activity,
+        mut loopback,
+        mut flow,
+        stats,
+    ) in q.iter_mut()
+    {
+        let mut frames = Vec::new();
+        // whether anything at all was read, frames or control messages
+        #[cfg(target_arch = "wasm32")]
+        let active = false;
+        #[cfg(not(target_arch = "wasm32"))]
+        let active = match client {
+            Some(client) => {
+                // what the read thread took off this socket since the last frame, else one message
+                let reads = match readers
+                    .get(entity)
+                    .ok()
+                    .filter(|reader| reader.reads(client))
+                {
+                    Some(reader) => reader
+                        .received
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .try_iter()
+                        .collect(),
+                    None => vec![client.socket().read()],
+                };
+                let mut active = false;
+                for read in reads {
+                    active |= read.is_ok();
+                    let closed = match read {
+                        Ok(Message::Binary(data)) => {
+                            frames.push((data, false));
+                            None
+                        }
+                        // the empty pongs answering `detect_stalls` probes aren't heartbeats
+                        Ok(Message::Pong(payload)) if parse_ping_payload(&payload).is_some() => {
+                            ev_heartbeat.send(HeartbeatReceived {
+                                entity,
+                                kind: HeartbeatKind::Pong,
+                                payload,
+                            });
+                            None
+                        }
+                        Ok(Message::Text(_)) if strict.0 => {
+                            ev_disconnect.send(Disconnect {
+                                entity,
+                                code: CloseCode::Protocol,
+                                reason: "unexpected text message".to_owned(),
+                            });
+                            None
+                        }
+                        // consumers get the UTF-8 bytes, just like for binary messages
+                        Ok(Message::Text(text)) => {
+                            frames.push((text.into_bytes(), true));
+                            None
+                        }
+                        // either the peer's answer to our close frame or the peer closing on its own
+                        Ok(Message::Close(frame)) => {
+                            let reason = frame.map_or("no reason given".to_owned(), |frame| {
+                                format!("{}: {}", frame.code, frame.reason)
+                            });
+                            info!("{entity} was closed by the peer ({reason})");
+                            // tungstenite queued the answering close frame, best effort since we're done
+                            let _ = client.socket().flush();
+                            Some(format!("closed by the peer ({reason})"))
+                        }
+                        Ok(m) => {
+                            info!("Received message {m:?}");
+                            None
+                        }
+                        Err(tungstenite::Error::Io(e)) if e.kind() == ErrorKind::WouldBlock => None,
+                        Err(e) => Some(e.to_string()),
+                    };
+                    if let Some(reason) = closed {
+                        let mut entity_commands = commands.entity(entity);
+                        entity_commands
+                            .remove::<WebSocketClient>()
+                            .insert(ConnectionState::Disconnected);
+                        if !resend_queued.0 {
+                            entity_commands.remove::<Outbox>();
+                        }
+                        let ev = ConnectionClosed { entity, reason };
+                        commands.trigger_targets(ev.clone(), entity);
+                        ev_closed.send(ev);
+                        break;
+                    }
+                }
+                active
+            }
+            None => false,
+        };
+        #[cfg(target_arch = "wasm32")]
+        if let Some(client) = client {
+            frames.extend(client.0.recv_queue.borrow_mut().drain(..));
+            let count = client.0.dropped.take();
+            if count > 0 {
+                ev_dropped.send(MessagesDropped { entity, count });
+            }
+        }
+        #[cfg(debug_assertions)]
+        if let Some(data) = injected.remove(&entity) {
+            frames.extend(data.into_iter().map(|data| (data, false)));
+        }
+
+        if let Some(mut activity) = activity.filter(|_| active || !frames.is_empty()) {
+            activity.last = time.elapsed();
+        }
+        let received = frames.iter().map(|(data, _)| data.len()).sum::<usize>();
+        bandwidth.received += received;
+        bandwidth.messages += frames.len();
+        if let Some(mut stats) = stats.filter(|_| !frames.is_empty()) {
+            stats.bytes_received += received as u64;
+            stats.messages_received += frames.len() as u64;
+        }
+        let tap = |stage, data: &[u8]| {
+            if let Some(tap) = &tap {
+                (tap.0)(entity, stage, data);
+            }
+        };
+        let mut batch = Vec::new();
+        for (mut data, text) in frames {
+            tap(WireStage::Incoming, &data);
+            if let Some((kind, payload)) = parse_heartbeat(&data) {
+                ev_heartbeat.send(HeartbeatReceived {
+                    entity,
+                    kind,
+                    payload: payload.to_vec(),
+                });
+                continue;
+            }
+            if let Some(bytes) = parse_credit_frame(&data) {
+                if let Some(flow) = flow.as_deref_mut() {
+                    flow.credit = flow.credit.saturating_add(bytes as usize);
+                }
+                continue;
+            }
+            if let Some(flow) = flow.as_deref_mut() {
+                flow.received += data.len();
+            }
+            if let Some(loopback) = loopback.as_deref_mut() {
+                if loopback.take_echo(frame_hash(&data)) {
+                    continue;
+                }
+            }
+            // text messages are sent as they are, see `SendText`
+            let channel = match multiplexed && !text {
+                true if data.is_empty() => {
+                    warn!("Received a frame without a channel, dropping it");
+                    continue;
+                }
+                true => Some(data.remove(0)),
+                false => None,
+            };
+            let data = match compression {
+                Some(compression) if !text => match compression.decode(&data) {
+                    Some(data) => data,
+                    None => {
+                        warn!("Could not decompress message or it's too large, dropping it");
+                        continue;
+                    }
+                },
+                _ => data,
+            };
+            let data = match diff.as_deref_mut() {
+                Some(diff) if !text => match diff.decode(&data) {
+                    Some(data) => data,
+                    None => {
+                        warn!("Could not apply binary diff, dropping message");
+                        continue;
+                    }
+                },
+                _ => data,
+            };
+            tap(WireStage::Decoded, &data);
+            if *receive_mode == ReceiveMode::Batched {
+                batch.push(ReceivedMessage {
+                    channel,
+                    data,
+                    text,
+                });
+                continue;
+            }
+            let ev = WebSocketMessageReceived {
+                entity,
+                channel,
+                data,
+                text,
+            };
+            commands.trigger_targets(ev.clone(), entity);
+            ev_received.send(ev);
+        }
+        if !batch.is_empty() {
+            let ev = WebSocketMessageBatch {
+                entity,
+                messages: batch,
+            };
+            commands.trigger_targets(ev.clone(), entity);
+            ev_batch.send(ev);
+        }
+    }
+    #[cfg(debug_assertions)]
+    for entity in injected.keys() {
+        warn!("Not injecting messages into {entity}: it isn't a connection");
+    }
+}
+
+/// The last `capacity` received messages, so systems added later (e.g. after a state
+/// transition) can catch up on what they missed. Zero, the default, keeps nothing.
+///
+/// Batched messages are kept one by one, like in [`ReceiveMode::PerMessage`].
+#[derive(Resource, Default)]
+pub struct ReplayBuffer {
+    capacity: usize,
+    messages: VecDeque<WebSocketMessageReceived>,
+}
+
+impl ReplayBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            messages: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Buffered messages, oldest first
+    pub fn iter(&self) -> impl Iterator<Item = &WebSocketMessageReceived> {
+        self.messages.iter()
+    }
+
+    fn push(&mut self, message: WebSocketMessageReceived) {
+        if self.capacity == 0 {
+            return;
+        }
+        while self.messages.len() >= self.capacity {
+            self.messages.pop_front();
+        }
+        self.messages.push_back(message);
+    }
+}
+
+pub fn record_replay(
+    mut replay: ResMut<ReplayBuffer>,
+    mut ev_received: EventReader<WebSocketMessageReceived>,
+    mut ev_batch: EventReader<WebSocketMessageBatch>,
+) {
+    for ev in ev_received.read() {
+        replay.push(ev.clone());
+    }
+    for WebSocketMessageBatch { entity, messages } in ev_batch.read() {
+        for ReceivedMessage {
+            channel,
+            data,
+            text,
+        } in messages
+        {
+            replay.push(WebSocketMessageReceived {
+                entity: *entity,
+                channel: *channel,
+                data: data.clone(),
+                text: *text,
+            });
+        }
+    }
+    // a capacity lowered at runtime applies right away
+    let capacity = replay.capacity;
+    let excess = replay.messages.len().saturating_sub(capacity);
+    replay.messages.drain(..excess);
+}
+
+pub fn log_received_batches(mut ev_batch: EventReader<WebSocketMessageBatch>) {
+    for WebSocketMessageBatch { entity, messages } in ev_batch.read() {
+        let sizes = messages
+            .iter()
+            .map(|ReceivedMessage { channel, data, .. }| (channel, data.len()))
+            .collect::<Vec<_>>();
+        info!("Received a batch from {entity} (channel, bytes): {sizes:?}");
+    }
+}
+
+pub fn log_dropped_messages(mut ev_dropped: EventReader<MessagesDropped>) {
+    for MessagesDropped { entity, count } in ev_dropped.read() {
+        warn!("Dropped {count} incoming messages for {entity}, the receive queue was full");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use bevy::ecs::system::RunSystemOnce;
+
+    use super::*;
+    use crate::{
+        testing::{app, connected_app, drain, echo, update_until},
+        ConnectionOpened, WebSocketConnectionEvents, WireFormat,
+    };
+
+    #[test]
+    fn receive_queue_overflow_policies() {
+        let mut oldest = VecDeque::new();
+        let mut newest = VecDeque::new();
+        let mut dropped = (0, 0);
+        for frame in 0..5 {
+            dropped.0 += usize::from(RecvOverflowPolicy::DropOldest.push(&mut oldest, frame, 3));
+            dropped.1 += usize::from(RecvOverflowPolicy::DropNewest.push(&mut newest, frame, 3));
+        }
+        assert_eq!(oldest, [2, 3, 4]);
+        assert_eq!(newest, [0, 1, 2]);
+        assert_eq!(dropped, (2, 2));
+    }
+
+    #[test]
+    fn read_threads_deliver_and_stop_after_reconnects() {
+        let (mut app, connection) = connected_app();
+        echo(&mut app, connection, b"before");
+
+        let world = app.world_mut();
+        let old = Arc::downgrade(&world.get::<WebSocketClient>(connection).unwrap().0 .0);
+        // read on our own from here on, so the old thread is observable
+        let old_thread = world
+            .entity_mut(connection)
+            .take::<ReadThreadReceiver>()
+            .expect("no read thread");
+        world.send_event(WebSocketConnectionEvents::Reconnect(connection));
+        update_until(&mut app, |app| drain::<ConnectionOpened>(app).pop());
+        app.update();
+
+        let world = app.world();
+        let client = world.get::<WebSocketClient>(connection).unwrap();
+        let thread = world.get::<ReadThreadReceiver>(connection).unwrap();
+        assert!(thread.reads(client));
+        assert!(!old_thread.reads(client));
+        // its thread hung up once it couldn't get at the socket anymore
+        let received = old_thread.received.lock().unwrap();
+        loop {
+            match received.recv_timeout(Duration::from_secs(5)) {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => panic!("the old thread failed instead of stopping: {e:?}"),
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                Err(mpsc::RecvTimeoutError::Timeout) => panic!("the old thread is still running"),
+            }
+        }
+        assert!(old.upgrade().is_none(), "the old socket is still around");
+        echo(&mut app, connection, b"after");
+    }
+
+    #[test]
+    fn batched_receiving_keeps_every_message_in_order() {
+        let mut app = app(Duration::from_millis(10));
+        app.insert_resource(ReceiveMode::Batched);
+        let connection = app.world_mut().spawn(Outbox::default()).id();
+        let sent: Vec<Vec<u8>> = (0..100u8).map(|i| vec![i; usize::from(i)]).collect();
+        for data in &sent {
+            app.world_mut().send_event(InjectInboundMessage {
+                entity: connection,
+                data: data.clone(),
+            });
+        }
+        app.update();
+        let batches = drain::<WebSocketMessageBatch>(&mut app);
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].entity, connection);
+        let received: Vec<_> = batches[0]
+            .messages
+            .iter()
+            .map(|msg| msg.data.clone())
+            .collect();
+        assert_eq!(received, sent);
+        assert!(drain::<WebSocketMessageReceived>(&mut app).is_empty());
+    }
+
+    #[test]
+    fn late_readers_catch_up_from_the_replay_buffer() {
+        let (mut app, connection) = connected_app();
+        app.insert_resource(ReplayBuffer::new(2));
+        for msg in [&b"one"[..], b"two", b"three"] {
+            echo(&mut app, connection, msg);
+        }
+        // a system that didn't exist while the messages came in
+        let replayed = app
+            .world_mut()
+            .run_system_once(|replay: Res<ReplayBuffer>| {
+                replay
+                    .iter()
+                    .map(|msg| msg.data.clone())
+                    .collect::<Vec<_>>()
+            });
+        assert_eq!(replayed, [b"two".to_vec(), b"three".to_vec()]);
+    }
+
+    #[test]
+    fn oversized_browser_messages_are_dropped_before_copying() {
+        assert_eq!(message_buffer(5, 5), Some(vec![0; 5]));
+        assert_eq!(message_buffer(6, 5), None);
+        // more than any allocator hands out, the browser could still report it
+        assert_eq!(message_buffer(usize::MAX, usize::MAX), None);
+    }
+
+    #[test]
+    fn browser_text_messages_are_limited_by_their_utf8_length() {
+        assert_eq!(text_message("hello".to_owned(), 5), Some(b"hello".to_vec()));
+        // five UTF-16 code units, but fifteen bytes
+        assert_eq!(text_message("€€€€€".to_owned(), 5), None);
+        assert_eq!(text_message("€€€€€".to_owned(), 15).unwrap().len(), 15);
+    }
+
+    #[test]
+    fn read_threads_receive_promptly_between_slow_frames() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}/", listener.local_addr().unwrap());
+        let (sent_at, sent) = mpsc::channel();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut socket = tungstenite::accept(stream).unwrap();
+            // once the client stopped updating
+            std::thread::sleep(Duration::from_millis(50));
+            sent_at.send(std::time::Instant::now()).unwrap();
+            socket.send(Message::binary(b"now".to_vec())).unwrap();
+            while socket.read().is_ok() {}
+        });
+        let mut app = app(Duration::from_millis(10));
+        app.world_mut()
+            .send_event(WebSocketConnectionEvents::SetupConnection(Some(url)));
+        let connection = update_until(&mut app, |app| drain::<ConnectionOpened>(app).pop()).entity;
+        app.update();
+        // no more updates, as if the frame took a long time
+        let thread = app.world().get::<ReadThreadReceiver>(connection).unwrap();
+        let received = thread.received.lock().unwrap();
+        let msg = received
+            .recv_timeout(Duration::from_secs(5))
+            .unwrap()
+            .unwrap();
+        let latency = sent.recv().unwrap().elapsed();
+        assert_eq!(msg, Message::binary(b"now".to_vec()));
+        assert!(latency < Duration::from_millis(100), "{latency:?}");
+        drop(received);
+        drop(app);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn injected_messages_reach_handlers_without_a_transport() {
+        #[derive(Resource, Default)]
+        struct Chat(Vec<String>);
+
+        let mut app = app(Duration::from_millis(10));
+        app.init_resource::<Chat>().add_systems(
+            Update,
+            |mut ev_received: EventReader<WebSocketMessageReceived>, mut chat: ResMut<Chat>| {
+                for received in ev_received.read() {
+                    chat.0
+                        .extend(WireFormat::Json.decode::<String>(&received.data));
+                }
+            },
+        );
+        let compression = Compression {
+            compress_threshold: 0,
+            ..default()
+        };
+        let connection = app
+            .world_mut()
+            .spawn((Outbox::default(), compression.clone()))
+            .id();
+        // compressed like off the socket, so it goes through the same decoding
+        let data = compression.encode(WireFormat::Json.encode(&"gg"));
+        app.world_mut().send_event(InjectInboundMessage {
+            entity: connection,
+            data,
+        });
+        app.update();
+        app.update();
+        assert_eq!(app.world().resource::<Chat>().0, ["gg"]);
+    }
+}
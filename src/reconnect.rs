@@ -0,0 +1,281 @@
+//! Reconnecting dropped connections following their [`ReconnectPolicy`].
+
+use std::time::Duration;
+
+use bevy::prelude::*;
+
+use crate::{
+    Closing, ConnectionClosed, ConnectionFailed, ConnectionOpened, ConnectionPermanentlyFailed,
+    ConnectionState, ServerUrl, WebSocketConnectionEvents,
+};
+
+/// How to retry connections that failed to connect or dropped
+#[derive(Resource)]
+pub struct ReconnectPolicy {
+    /// Wait before the first retry. Zero retries on the next frame, with the backoff
+    /// starting from [`MIN_RECONNECT_BACKOFF`] on later retries.
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    /// Give up after this many failed attempts in a row with a [`ConnectionPermanentlyFailed`],
+    /// `None` retries forever
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+            max_attempts: None,
+        }
+    }
+}
+
+/// Backoff step used after an instant first retry, so a zero `base_delay` doesn't retry every frame
+pub const MIN_RECONNECT_BACKOFF: Duration = Duration::from_millis(100);
+
+impl ReconnectPolicy {
+    /// Exponential backoff for retry number `attempt`, starting at 1
+    fn delay(&self, attempt: u32) -> Duration {
+        if self.base_delay.is_zero() {
+            if attempt <= 1 {
+                return Duration::ZERO;
+            }
+            let factor = 2u32.saturating_pow(attempt - 2);
+            return MIN_RECONNECT_BACKOFF
+                .saturating_mul(factor)
+                .min(self.max_delay);
+        }
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.base_delay.saturating_mul(factor).min(self.max_delay)
+    }
+}
+
+/// Whether to keep reconnecting while no window has focus.
+///
+/// Turning this off saves battery in backgrounded browser tabs, where reconnects
+/// are throttled and often fail anyway. Pending reconnects resume on focus.
+#[derive(Resource)]
+pub struct ReconnectWhileUnfocused(pub bool);
+
+/// Failed connection attempts in a row, reset once a connection opens
+#[derive(Component, Default)]
+pub struct ReconnectAttempts(pub(crate) u32);
+
+/// Counts down to the next reconnect attempt
+#[derive(Component)]
+pub struct ReconnectTimer(Timer);
+
+#[allow(clippy::too_many_arguments)]
+pub fn schedule_reconnects(
+    mut commands: Commands,
+    mut ev_failed: EventReader<ConnectionFailed>,
+    mut ev_closed: EventReader<ConnectionClosed>,
+    mut ev_opened: EventReader<ConnectionOpened>,
+    mut attempts: Query<&mut ReconnectAttempts>,
+    closing: Query<(), With<Closing>>,
+    reconnectable: Query<(), With<ServerUrl>>,
+    policy: Res<ReconnectPolicy>,
+    mut ev_gave_up: EventWriter<ConnectionPermanentlyFailed>,
+) {
+    for ConnectionOpened { entity, .. } in ev_opened.read() {
+        if let Ok(mut attempts) = attempts.get_mut(*entity) {
+            attempts.0 = 0;
+        }
+    }
+    let mut lost = ev_failed.read().map(|ev| ev.entity).collect::<Vec<_>>();
+    for ConnectionClosed { entity, .. } in ev_closed.read() {
+        if closing.contains(*entity) {
+            // we closed it ourselves, so it's done
+            commands.entity(*entity).remove::<Closing>();
+        } else {
+            lost.push(*entity);
+        }
+    }
+    for entity in lost {
+        if !reconnectable.contains(entity) {
+            continue; // a peer that connected to us, it's up to them to come back
+        }
+        let attempt = attempts.get(entity).map_or(0, |a| a.0) + 1;
+        if policy.max_attempts.is_some_and(|max| attempt > max) {
+            let attempts = attempt - 1;
+            if let Some(mut entity_commands) = commands.get_entity(entity) {
+                entity_commands
+                    .remove::<ReconnectAttempts>()
+                    .insert(ConnectionState::Failed);
+            }
+            let ev = ConnectionPermanentlyFailed { entity, attempts };
+            commands.trigger_targets(ev.clone(), entity);
+            ev_gave_up.send(ev);
+            continue;
+        }
+        let delay = policy.delay(attempt);
+        info!("Reconnecting {entity} in {delay:?}");
+        if let Some(mut entity_commands) = commands.get_entity(entity) {
+            entity_commands.insert((
+                ReconnectAttempts(attempt),
+                ReconnectTimer(Timer::new(delay, TimerMode::Once)),
+            ));
+        }
+    }
+}
+
+pub fn reconnect(
+    mut commands: Commands,
+    mut timers: Query<(Entity, &mut ReconnectTimer)>,
+    time: Res<Time>,
+    windows: Query<&Window>,
+    while_unfocused: Res<ReconnectWhileUnfocused>,
+    mut ev_connect: EventWriter<WebSocketConnectionEvents>,
+) {
+    if !while_unfocused.0 && !windows.iter().any(|window| window.focused) {
+        return; // paused until we get focus back
+    }
+    for (entity, mut timer) in &mut timers {
+        if timer.0.tick(time.delta()).finished() {
+            commands.entity(entity).remove::<ReconnectTimer>();
+            ev_connect.send(WebSocketConnectionEvents::Reconnect(entity));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testing::{app, connected_app, drain, refused_url, update_until},
+        LocalEchoServer, MessageSendOutcome, ResendQueuedOnReconnect, SendTo, SendWhileClosed,
+        WebSocketMessageReceived,
+    };
+
+    #[test]
+    fn connections_fail_for_good_once_reconnects_run_out() {
+        let mut app = app(Duration::from_millis(50));
+        app.insert_resource(ReconnectPolicy {
+            base_delay: Duration::ZERO,
+            max_delay: Duration::from_millis(200),
+            max_attempts: Some(2),
+        });
+        app.world_mut()
+            .send_event(WebSocketConnectionEvents::SetupConnection(Some(
+                refused_url().0,
+            )));
+        let mut failures = 0;
+        let gave_up = update_until(&mut app, |app| {
+            failures += drain::<ConnectionFailed>(app).len();
+            drain::<ConnectionPermanentlyFailed>(app).pop()
+        });
+        assert_eq!(gave_up.attempts, 2);
+        // the first attempt and both retries
+        assert_eq!(failures, 3);
+        for _ in 0..10 {
+            app.update();
+        }
+        let state = app.world().get::<ConnectionState>(gave_up.entity);
+        assert_eq!(state, Some(&ConnectionState::Failed));
+        assert!(
+            drain::<ConnectionFailed>(&mut app).is_empty(),
+            "still reconnecting"
+        );
+    }
+
+    #[test]
+    fn reconnects_wait_for_focus_when_asked_to() {
+        let mut app = app(Duration::from_millis(10));
+        app.insert_resource(ReconnectWhileUnfocused(false));
+        let window = app
+            .world_mut()
+            .spawn(Window {
+                focused: false,
+                ..default()
+            })
+            .id();
+        let connection = app
+            .world_mut()
+            .spawn(ReconnectTimer(Timer::new(Duration::ZERO, TimerMode::Once)))
+            .id();
+        let reconnects = |app: &mut App| {
+            app.update();
+            drain::<WebSocketConnectionEvents>(app)
+                .into_iter()
+                .filter(
+                    |ev| matches!(ev, WebSocketConnectionEvents::Reconnect(e) if *e == connection),
+                )
+                .count()
+        };
+        for _ in 0..5 {
+            assert_eq!(reconnects(&mut app), 0);
+        }
+        app.world_mut().get_mut::<Window>(window).unwrap().focused = true;
+        assert_eq!(reconnects(&mut app), 1);
+        assert!(app.world().get::<ReconnectTimer>(connection).is_none());
+    }
+
+    #[test]
+    fn reconnects_without_delay_retry_on_the_next_frame() {
+        let mut app = app(Duration::from_millis(10));
+        app.insert_resource(ReconnectPolicy {
+            base_delay: Duration::ZERO,
+            ..default()
+        });
+        app.world_mut()
+            .send_event(WebSocketConnectionEvents::SetupConnection(Some(
+                refused_url().0,
+            )));
+        let reconnects = |app: &mut App| {
+            drain::<WebSocketConnectionEvents>(app)
+                .iter()
+                .filter(|ev| matches!(ev, WebSocketConnectionEvents::Reconnect(_)))
+                .count()
+        };
+        update_until(&mut app, |app| drain::<ConnectionFailed>(app).pop());
+        let mut frames = 0;
+        while reconnects(&mut app) == 0 {
+            app.update();
+            frames += 1;
+        }
+        assert!(frames <= 1, "retried after {frames} frames");
+        let policy = app.world().resource::<ReconnectPolicy>();
+        assert_eq!(policy.delay(2), MIN_RECONNECT_BACKOFF);
+        assert_eq!(policy.delay(3), MIN_RECONNECT_BACKOFF * 2);
+    }
+
+    #[test]
+    fn messages_queued_while_dropped_are_resent_only_if_asked_to() {
+        for resend in [true, false] {
+            let (mut app, connection) = connected_app();
+            app.insert_resource(ResendQueuedOnReconnect(resend))
+                .insert_resource(SendWhileClosed::Queue)
+                .insert_resource(ReconnectPolicy {
+                    base_delay: Duration::from_secs(3600),
+                    ..default()
+                });
+            app.world_mut().remove_resource::<LocalEchoServer>();
+            update_until(&mut app, |app| drain::<ConnectionClosed>(app).pop());
+            app.world_mut().send_event(SendTo {
+                entity: connection,
+                data: b"while away".to_vec(),
+            });
+            app.update();
+            let outcomes = drain::<MessageSendOutcome>(&mut app);
+            assert_eq!(outcomes.is_empty(), resend, "queued: {resend}");
+
+            // back on another server
+            let server = LocalEchoServer::start().unwrap();
+            app.world_mut().get_mut::<ServerUrl>(connection).unwrap().0 = server.url().to_owned();
+            app.insert_resource(server);
+            app.world_mut()
+                .send_event(WebSocketConnectionEvents::Reconnect(connection));
+            update_until(&mut app, |app| drain::<ConnectionOpened>(app).pop());
+            let mut echoed = false;
+            for _ in 0..50 {
+                app.update();
+                echoed |= drain::<WebSocketMessageReceived>(&mut app)
+                    .iter()
+                    .any(|received| received.data == b"while away");
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            assert_eq!(echoed, resend, "resent: {resend}");
+        }
+    }
+}
@@ -0,0 +1,447 @@
+//! Delivery that survives reconnects with [`Reliable`], and [`Subscriptions`] that are reissued.
+
+use std::collections::VecDeque;
+
+use bevy::prelude::*;
+
+use crate::{
+    enqueue, BinaryDiff, Compression, Multiplexed, Outbox, OutboxBudgetExceeded,
+    OutboxMemoryBudget, WebSocketClient, WebSocketMessageReceived, WireTap, GAME_STATE_CHANNEL,
+};
+
+/// Sequence and ack state for [`SendReliable`] messages. It lives on the connection entity,
+/// which is reused across reconnects, so a reconnect resumes where the last one stopped:
+/// whatever the peer hadn't acked yet is sent again and the peer drops what it already
+/// delivered. Both peers need this component.
+///
+/// Peers that connected to our server are a new entity for every connection, so only the
+/// client side resumes.
+#[derive(Component, Default)]
+pub struct Reliable {
+    /// Sequence number of the next message we send, starting at 1
+    next_seq: u64,
+    /// Sent messages the peer hasn't acked, oldest first
+    unacked: VecDeque<(u64, Vec<u8>)>,
+    /// Highest sequence number delivered in order, 0 before the first one
+    received: u64,
+}
+
+/// Marks a reliable message, followed by its big endian `u64` sequence number
+pub const RELIABLE_DATA_TAG: [u8; 4] = [0xff, b'r', b'e', b'l'];
+/// Marks a cumulative ack, followed by the big endian `u64` of the last delivered sequence number
+pub const RELIABLE_ACK_TAG: [u8; 4] = [0xff, b'r', b'a', b'k'];
+
+fn reliable_frame(tag: [u8; 4], seq: u64, data: &[u8]) -> Vec<u8> {
+    [&tag[..], &seq.to_be_bytes(), data].concat()
+}
+
+fn parse_reliable_frame(tag: [u8; 4], frame: &[u8]) -> Option<(u64, &[u8])> {
+    let rest = frame.strip_prefix(&tag[..])?;
+    let (seq, data) = rest.split_first_chunk::<8>()?;
+    Some((u64::from_be_bytes(*seq), data))
+}
+
+/// Send `data` with at-least-once delivery, the peer delivers it exactly once as a
+/// [`ReliableMessageReceived`]. Needs [`Reliable`] on the connection.
+#[derive(Event)]
+pub struct SendReliable {
+    pub entity: Entity,
+    pub data: Vec<u8>,
+}
+
+#[derive(Event, Clone)]
+pub struct ReliableMessageReceived {
+    pub entity: Entity,
+    pub seq: u64,
+    pub data: Vec<u8>,
+}
+
+pub type ReliableSender<'a> = (
+    Entity,
+    Option<Mut<'a, Outbox>>,
+    Option<Mut<'a, BinaryDiff>>,
+    Option<&'a Compression>,
+    bool,
+);
+
+fn enqueue_reliable(
+    (entity, outbox, diff, compression, multiplexed): ReliableSender,
+    msg: Vec<u8>,
+    budget: &mut OutboxMemoryBudget,
+    ev_exceeded: &mut EventWriter<OutboxBudgetExceeded>,
+    tap: Option<&WireTap>,
+) {
+    // without a connection there's nothing to queue on, unacked messages go out on reconnect
+    let Some(mut outbox) = outbox else {
+        return;
+    };
+    let channel = multiplexed.then_some(GAME_STATE_CHANNEL);
+    enqueue(
+        entity,
+        channel,
+        msg,
+        &mut outbox,
+        diff,
+        compression,
+        budget,
+        ev_exceeded,
+        tap,
+    );
+}
+
+#[allow(clippy::type_complexity)]
+pub fn send_reliable(
+    mut ev_send: EventReader<SendReliable>,
+    mut q: Query<(
+        &mut Reliable,
+        (
+            Entity,
+            Option<&mut Outbox>,
+            Option<&mut BinaryDiff>,
+            Option<&Compression>,
+            Has<Multiplexed>,
+        ),
+    )>,
+    mut budget: ResMut<OutboxMemoryBudget>,
+    mut ev_exceeded: EventWriter<OutboxBudgetExceeded>,
+    tap: Option<Res<WireTap>>,
+) {
+    for SendReliable { entity, data } in ev_send.read() {
+        let Ok((mut reliable, sender)) = q.get_mut(*entity) else {
+            warn!("Not sending reliably to {entity}: it has no Reliable component");
+            continue;
+        };
+        reliable.next_seq = reliable.next_seq.max(1);
+        let seq = reliable.next_seq;
+        reliable.next_seq += 1;
+        reliable.unacked.push_back((seq, data.clone()));
+        enqueue_reliable(
+            sender,
+            reliable_frame(RELIABLE_DATA_TAG, seq, data),
+            &mut budget,
+            &mut ev_exceeded,
+            tap.as_deref(),
+        );
+    }
+}
+
+/// Topics a connection is subscribed to on a pub/sub server. They're subscribed to whenever
+/// the connection opens, so they survive reconnects. Record topics before connecting, or
+/// send the first subscribe yourself when adding one to an open connection.
+#[derive(Component, Default)]
+pub struct Subscriptions(Vec<String>);
+
+impl Subscriptions {
+    pub fn subscribe(&mut self, topic: impl Into<String>) {
+        let topic = topic.into();
+        if !self.0.contains(&topic) {
+            self.0.push(topic);
+        }
+    }
+
+    pub fn unsubscribe(&mut self, topic: &str) {
+        self.0.retain(|subscribed| subscribed != topic);
+    }
+}
+
+/// Turns a topic of [`Subscriptions`] into the message subscribing to it, by default
+/// `{"subscribe":"<topic>"}`
+#[allow(clippy::type_complexity)]
+#[derive(Resource)]
+pub struct SubscribeMessage(Box<dyn Fn(&str) -> Vec<u8> + Send + Sync>);
+
+impl SubscribeMessage {
+    pub fn new(message: impl Fn(&str) -> Vec<u8> + Send + Sync + 'static) -> Self {
+        Self(Box::new(message))
+    }
+}
+
+impl Default for SubscribeMessage {
+    fn default() -> Self {
+        Self(Box::new(|topic| {
+            serde_json::to_vec(&serde_json::json!({ "subscribe": topic })).unwrap()
+        }))
+    }
+}
+
+#[allow(clippy::type_complexity)]
+pub fn resubscribe(
+    mut q: Query<
+        (
+            Entity,
+            &Subscriptions,
+            &mut Outbox,
+            Option<&mut BinaryDiff>,
+            Option<&Compression>,
+        ),
+        Added<WebSocketClient>,
+    >,
+    message: Res<SubscribeMessage>,
+    mut budget: ResMut<OutboxMemoryBudget>,
+    mut ev_exceeded: EventWriter<OutboxBudgetExceeded>,
+    tap: Option<Res<WireTap>>,
+) {
+    for (entity, subscriptions, mut outbox, mut diff, compression) in &mut q {
+        if !subscriptions.0.is_empty() {
+            info!("Subscribing {entity} to {:?}", subscriptions.0);
+        }
+        for topic in &subscriptions.0 {
+            enqueue(
+                entity,
+                None,
+                (message.0)(topic),
+                &mut outbox,
+                diff.as_mut().map(Mut::reborrow),
+                compression,
+                &mut budget,
+                &mut ev_exceeded,
+                tap.as_deref(),
+            );
+        }
+    }
+}
+
+/// Send everything the peer hasn't acked again once a connection (re)opens
+#[allow(clippy::type_complexity)]
+pub fn resend_unacked(
+    mut q: Query<
+        (
+            &Reliable,
+            (
+                Entity,
+                Option<&mut Outbox>,
+                Option<&mut BinaryDiff>,
+                Option<&Compression>,
+                Has<Multiplexed>,
+            ),
+        ),
+        Added<WebSocketClient>,
+    >,
+    mut budget: ResMut<OutboxMemoryBudget>,
+    mut ev_exceeded: EventWriter<OutboxBudgetExceeded>,
+    tap: Option<Res<WireTap>>,
+) {
+    for (reliable, (entity, mut outbox, mut diff, compression, multiplexed)) in &mut q {
+        if !reliable.unacked.is_empty() {
+            info!(
+                "Resending {} unacked messages to {entity}",
+                reliable.unacked.len()
+            );
+        }
+        for (seq, data) in &reliable.unacked {
+            enqueue_reliable(
+                (
+                    entity,
+                    outbox.as_mut().map(Mut::reborrow),
+                    diff.as_mut().map(Mut::reborrow),
+                    compression,
+                    multiplexed,
+                ),
+                reliable_frame(RELIABLE_DATA_TAG, *seq, data),
+                &mut budget,
+                &mut ev_exceeded,
+                tap.as_deref(),
+            );
+        }
+    }
+}
+
+/// Deliver reliable messages in order and once, ack them, and forget what the peer acked
+#[allow(clippy::type_complexity)]
+pub fn receive_reliable(
+    mut ev_received: EventReader<WebSocketMessageReceived>,
+    mut q: Query<(
+        &mut Reliable,
+        (
+            Entity,
+            Option<&mut Outbox>,
+            Option<&mut BinaryDiff>,
+            Option<&Compression>,
+            Has<Multiplexed>,
+        ),
+    )>,
+    mut budget: ResMut<OutboxMemoryBudget>,
+    mut ev_exceeded: EventWriter<OutboxBudgetExceeded>,
+    tap: Option<Res<WireTap>>,
+    mut ev_reliable: EventWriter<ReliableMessageReceived>,
+) {
+    for WebSocketMessageReceived { entity, data, .. } in ev_received.read() {
+        let Ok((mut reliable, sender)) = q.get_mut(*entity) else {
+            continue;
+        };
+        if let Some((acked, _)) = parse_reliable_frame(RELIABLE_ACK_TAG, data) {
+            while reliable
+                .unacked
+                .front()
+                .is_some_and(|(seq, _)| *seq <= acked)
+            {
+                reliable.unacked.pop_front();
+            }
+            continue;
+        }
+        let Some((seq, data)) = parse_reliable_frame(RELIABLE_DATA_TAG, data) else {
+            continue;
+        };
+        if seq == reliable.received + 1 {
+            reliable.received = seq;
+            ev_reliable.send(ReliableMessageReceived {
+                entity: *entity,
+                seq,
+                data: data.to_vec(),
+            });
+        } else if seq > reliable.received {
+            // the frames in between were lost with a connection, the peer resends from there
+            warn!(
+                "Dropping reliable message {seq} from {entity}, expected {}",
+                reliable.received + 1
+            );
+        }
+        // ack duplicates too, the ack for the original may have been lost
+        enqueue_reliable(
+            sender,
+            reliable_frame(RELIABLE_ACK_TAG, reliable.received, &[]),
+            &mut budget,
+            &mut ev_exceeded,
+            tap.as_deref(),
+        );
+    }
+}
+
+pub fn log_reliable_messages(mut ev_reliable: EventReader<ReliableMessageReceived>) {
+    for ReliableMessageReceived { entity, seq, data } in ev_reliable.read() {
+        info!("Received reliable message {seq} from {entity}: {data:?}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, time::Duration};
+
+    use tungstenite::Message;
+
+    use super::*;
+    use crate::{
+        testing::{app, drain, update_until},
+        ConnectionClosed, ConnectionOpened, ReconnectPolicy, ServerUrl, WebSocketConnectionEvents,
+    };
+
+    #[test]
+    fn reliable_messages_survive_reconnects_exactly_once() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}/", listener.local_addr().unwrap());
+        // a peer that delivers like `receive_reliable`, but drops the first connection early
+        let server = std::thread::spawn(move || {
+            let mut received = 0;
+            let mut delivered = Vec::new();
+            for (connection, down) in [(0, &[1][..]), (1, &[1, 2][..])] {
+                let (stream, _) = listener.accept().unwrap();
+                let mut socket = tungstenite::accept(stream).unwrap();
+                // the first connection's message 1 again, as if the client's ack got lost
+                for seq in down {
+                    let data = format!("down {seq}").into_bytes();
+                    let frame = reliable_frame(RELIABLE_DATA_TAG, *seq, &data);
+                    socket.send(Message::binary(frame)).unwrap();
+                }
+                while delivered.len() < [3, 5][connection] {
+                    let Message::Binary(frame) = socket.read().unwrap() else {
+                        continue;
+                    };
+                    let Some((seq, data)) = parse_reliable_frame(RELIABLE_DATA_TAG, &frame) else {
+                        continue;
+                    };
+                    if seq == received + 1 {
+                        received = seq;
+                        delivered.push(data.to_vec());
+                    }
+                    // only ever ack the first, the rest is lost with the connection
+                    if seq == 1 {
+                        let ack = reliable_frame(RELIABLE_ACK_TAG, 1, &[]);
+                        socket.send(Message::binary(ack)).unwrap();
+                    }
+                }
+            }
+            delivered
+        });
+        let mut app = app(Duration::from_millis(10));
+        app.insert_resource(ReconnectPolicy {
+            base_delay: Duration::from_secs(3600),
+            ..default()
+        });
+        app.world_mut()
+            .send_event(WebSocketConnectionEvents::SetupConnection(Some(url)));
+        let connection = update_until(&mut app, |app| drain::<ConnectionOpened>(app).pop()).entity;
+        let up = |app: &mut App, seq: u8| {
+            app.world_mut().send_event(SendReliable {
+                entity: connection,
+                data: vec![seq],
+            });
+        };
+        for seq in 1..=3 {
+            up(&mut app, seq);
+        }
+        let mut down = Vec::new();
+        update_until(&mut app, |app| {
+            down.extend(drain::<ReliableMessageReceived>(app));
+            drain::<ConnectionClosed>(app).pop()
+        });
+        for seq in 4..=5 {
+            up(&mut app, seq);
+        }
+        app.world_mut()
+            .send_event(WebSocketConnectionEvents::Reconnect(connection));
+        update_until(&mut app, |app| {
+            down.extend(drain::<ReliableMessageReceived>(app));
+            server.is_finished().then_some(())
+        });
+        let delivered = server.join().unwrap();
+        assert_eq!(delivered, (1..=5).map(|seq| vec![seq]).collect::<Vec<_>>());
+        // what the server sent last may not have been read yet
+        for _ in 0..20 {
+            app.update();
+            down.extend(drain::<ReliableMessageReceived>(&mut app));
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        let down: Vec<_> = down.iter().map(|msg| (msg.seq, msg.data.clone())).collect();
+        assert_eq!(down, [(1, b"down 1".to_vec()), (2, b"down 2".to_vec())]);
+    }
+
+    #[test]
+    fn subscriptions_are_reissued_on_reconnect() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}/", listener.local_addr().unwrap());
+        // records what each connection subscribes to, then drops it
+        let server = std::thread::spawn(move || {
+            (0..2)
+                .map(|_| {
+                    let (stream, _) = listener.accept().unwrap();
+                    let mut socket = tungstenite::accept(stream).unwrap();
+                    let mut topics = Vec::new();
+                    while topics.len() < 2 {
+                        if let Message::Binary(data) = socket.read().unwrap() {
+                            topics.push(String::from_utf8(data).unwrap());
+                        }
+                    }
+                    topics
+                })
+                .collect::<Vec<_>>()
+        });
+        let mut app = app(Duration::from_millis(10));
+        app.insert_resource(SubscribeMessage::new(|topic| {
+            format!("SUB {topic}").into_bytes()
+        }))
+        .insert_resource(ReconnectPolicy {
+            base_delay: Duration::ZERO,
+            ..default()
+        });
+        let mut subscriptions = Subscriptions::default();
+        subscriptions.subscribe("chat");
+        subscriptions.subscribe("scores");
+        subscriptions.subscribe("chat");
+        let connection = app.world_mut().spawn((ServerUrl(url), subscriptions)).id();
+        app.world_mut()
+            .send_event(WebSocketConnectionEvents::Reconnect(connection));
+        update_until(&mut app, |_| server.is_finished().then_some(()));
+        let subscribed = server.join().unwrap();
+        assert_eq!(subscribed, [["SUB chat", "SUB scores"]; 2]);
+    }
+}
@@ -0,0 +1,644 @@
+//! Replicating transforms between peers: [`NetworkId`]s, replicas and smoothing.
+
+use std::time::Duration;
+
+use avian3d::prelude::*;
+use bevy::{
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    decode_transforms, WebSocketMessageReceived, WireFormat, GAME_STATE_CHANNEL, RELIABLE_ACK_TAG,
+    RELIABLE_DATA_TAG,
+};
+
+/// The last received transform of a [`RemoteReplica`], which its [`Transform`] moves towards
+/// at the [`RemoteSmoothing`] rate instead of jumping there
+#[derive(Component)]
+pub struct TargetTransform(pub Transform);
+
+/// How quickly replicas catch up with their [`TargetTransform`]: the part of the way left
+/// that they cover per second is `1 - e^-rate`. Zero jumps straight there.
+#[derive(Resource)]
+pub struct RemoteSmoothing(pub f32);
+
+impl Default for RemoteSmoothing {
+    fn default() -> Self {
+        Self(10.0)
+    }
+}
+
+/// Turn the transforms `send_info` sends into [`ReplicaUpdate`]s
+pub fn apply_remote_transforms(
+    mut ev_received: EventReader<WebSocketMessageReceived>,
+    hook: Res<RecvTransformHook>,
+    formats: Query<&WireFormat>,
+    mut ev_update: EventWriter<ReplicaUpdate>,
+) {
+    for WebSocketMessageReceived {
+        entity,
+        channel,
+        data,
+        ..
+    } in ev_received.read()
+    {
+        if channel.is_some_and(|channel| channel != GAME_STATE_CHANNEL)
+            || data.starts_with(&RELIABLE_DATA_TAG)
+            || data.starts_with(&RELIABLE_ACK_TAG)
+        {
+            continue;
+        }
+        let format = formats.get(*entity).copied().unwrap_or_default();
+        // not every message is a state update
+        let Some(transforms) = decode_transforms(format, data) else {
+            continue;
+        };
+        ev_update.send_batch(transforms.into_iter().map(|(id, transform)| ReplicaUpdate {
+            id,
+            transform: (hook.0)(&transform),
+            velocity: None,
+        }));
+    }
+}
+
+pub fn interpolate_remote_transforms(
+    mut replicas: Query<(&mut Transform, &TargetTransform)>,
+    smoothing: Res<RemoteSmoothing>,
+    time: Res<Time>,
+) {
+    if smoothing.0 <= 0.0 {
+        return; // `apply_replica_updates` already put them there
+    }
+    // framerate independent, unlike a fixed share per frame
+    let t = 1.0 - (-smoothing.0 * time.delta_seconds()).exp();
+    for (mut transform, TargetTransform(target)) in &mut replicas {
+        transform.translation = transform.translation.lerp(target.translation, t);
+        transform.rotation = transform.rotation.slerp(target.rotation, t);
+        transform.scale = transform.scale.lerp(target.scale, t);
+    }
+}
+
+/// Converts outgoing transforms to the wire convention, e.g. when the server uses Z-up
+#[derive(Resource)]
+pub struct SendTransformHook(pub Box<dyn Fn(&Transform) -> Transform + Send + Sync>);
+
+impl Default for SendTransformHook {
+    fn default() -> Self {
+        Self(Box::new(|t| *t))
+    }
+}
+
+/// Converts incoming transforms from the wire convention back to the local one,
+/// the counterpart to [`SendTransformHook`]
+#[derive(Resource)]
+pub struct RecvTransformHook(pub Box<dyn Fn(&Transform) -> Transform + Send + Sync>);
+
+impl Default for RecvTransformHook {
+    fn default() -> Self {
+        Self(Box::new(|t| *t))
+    }
+}
+
+/// An entity mirrored from data received over the network
+#[derive(Component)]
+pub struct RemoteReplica {
+    /// [`Time::elapsed`] when the last update for this replica arrived
+    last_update: Duration,
+}
+
+/// Identifies a replicated entity across peers, local [`Entity`] ids differ between them
+#[derive(Component, Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+pub struct NetworkId(pub u64);
+
+impl NetworkId {
+    /// What `entity` is replicated as: its own id, or else one only stable for this run
+    pub fn or_entity(id: Option<&NetworkId>, entity: Entity) -> Self {
+        id.copied().unwrap_or(NetworkId(entity.to_bits()))
+    }
+}
+
+/// Give dynamic bodies spawned without a [`NetworkId`] the next one, counting up from `next`,
+/// so their replicas on the other side outlive a restart as long as both peers spawn them in
+/// the same order. Off unless the resource is inserted; without an id entities are sent under
+/// their [`Entity`], which changes from run to run.
+#[derive(Resource)]
+pub struct AutoNetworkIds {
+    next: u64,
+    /// Number every other new entity with a [`Transform`] too, for [`ReplicateTransforms`]
+    transforms: bool,
+}
+
+impl Default for AutoNetworkIds {
+    fn default() -> Self {
+        Self {
+            // well clear of ids picked by hand
+            next: 1 << 32,
+            transforms: false,
+        }
+    }
+}
+
+#[allow(clippy::type_complexity)]
+pub fn assign_network_ids(
+    mut commands: Commands,
+    bodies: Query<(Entity, &RigidBody), (Added<RigidBody>, Without<NetworkId>)>,
+    transforms: Query<Entity, (Added<Transform>, Without<NetworkId>, Without<RemoteReplica>)>,
+    mut ids: ResMut<AutoNetworkIds>,
+) {
+    let bodies = bodies
+        .iter()
+        .filter(|(_, body)| body.is_dynamic())
+        .map(|(entity, _)| entity);
+    let transforms = transforms.iter().filter(|_| ids.transforms);
+    // in query order, so peers spawning the same things get the same ids
+    let mut seen = HashSet::new();
+    let new = bodies
+        .chain(transforms)
+        .filter(|entity| seen.insert(*entity));
+    for entity in new.collect::<Vec<_>>() {
+        commands.entity(entity).insert(NetworkId(ids.next));
+        ids.next += 1;
+    }
+}
+
+/// Only ever replicate these entities on this connection, e.g. for a spectator
+/// watching specific players. Connections without a scope replicate everything.
+#[derive(Component, Clone, Default)]
+pub struct ReplicationScope(pub Vec<Entity>);
+
+/// Send every [`Transform`] on this connection, see [`send_info`](crate::send_info). Opt-in, since
+/// a client talking to e.g. a chat and a game server rarely wants the same updates going to both.
+#[derive(Component)]
+pub struct ReplicateTransforms;
+
+/// Which local entity mirrors each [`NetworkId`], so updates for the same id keep
+/// landing on the same entity instead of spawning a new one every time
+#[derive(Resource, Default)]
+pub struct ReplicaRegistry(HashMap<NetworkId, Entity>);
+
+impl ReplicaRegistry {
+    pub(crate) fn get(&self, id: NetworkId) -> Option<Entity> {
+        self.0.get(&id).copied()
+    }
+}
+
+/// An [`Entity`] inside a replicated message, e.g. the parent or target of a component.
+/// Local entities mean nothing to the receiver, so it goes over the wire as the
+/// [`NetworkId`] both sides know it by.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+pub struct NetworkedEntity(NetworkId);
+
+impl NetworkedEntity {
+    /// Refer to a local entity, under the same id its own replication uses
+    pub fn from_local(entity: Entity, ids: &Query<&NetworkId>) -> Self {
+        Self(NetworkId::or_entity(ids.get(entity).ok(), entity))
+    }
+
+    /// The replica standing in for the referenced entity, `None` until its first update
+    /// arrives or after it went stale
+    pub fn to_local(self, registry: &ReplicaRegistry) -> Option<Entity> {
+        registry.get(self.0)
+    }
+}
+
+/// Which state to apply when one replication message has several for the same [`NetworkId`],
+/// which only a buggy peer sends
+#[derive(Resource, Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub enum DuplicateNetworkIds {
+    /// The one that comes last in the message
+    #[default]
+    LastWins,
+    /// The one that comes first in the message
+    FirstWins,
+    /// None of them, the replica keeps its previous state
+    Drop,
+}
+
+impl DuplicateNetworkIds {
+    /// Leave at most one state per id, keeping the order of the message otherwise
+    pub fn dedup<T>(self, states: Vec<T>, id: impl Fn(&T) -> NetworkId) -> Vec<T> {
+        let mut counts = HashMap::<NetworkId, usize>::new();
+        for state in &states {
+            *counts.entry(id(state)).or_default() += 1;
+        }
+        let mut seen = HashMap::<NetworkId, usize>::new();
+        states
+            .into_iter()
+            .filter(|state| {
+                let count = counts[&id(state)];
+                let seen = seen.entry(id(state)).or_default();
+                *seen += 1;
+                match self {
+                    _ if count == 1 => true,
+                    DuplicateNetworkIds::LastWins => *seen == count,
+                    DuplicateNetworkIds::FirstWins => *seen == 1,
+                    DuplicateNetworkIds::Drop => false,
+                }
+            })
+            .collect()
+    }
+}
+
+/// New state for a replica, decoded by the app from its replication messages
+#[derive(Event)]
+pub struct ReplicaUpdate {
+    pub id: NetworkId,
+    pub transform: Transform,
+    /// Linear and angular velocity, for replicas of physics bodies
+    pub velocity: Option<(LinearVelocity, AngularVelocity)>,
+}
+
+/// Applies every update in one pass: replicas that exist are written in place and new ones
+/// spawned together, which matters once thousands of replicas update every frame
+#[allow(clippy::type_complexity)]
+pub fn apply_replica_updates(
+    mut commands: Commands,
+    mut ev_update: EventReader<ReplicaUpdate>,
+    registry: Res<ReplicaRegistry>,
+    mut replicas: Query<(
+        &mut Transform,
+        &mut TargetTransform,
+        &mut RemoteReplica,
+        Option<&mut LinearVelocity>,
+        Option<&mut AngularVelocity>,
+    )>,
+    smoothing: Res<RemoteSmoothing>,
+    time: Res<Time>,
+) {
+    // only the newest update per id matters
+    let latest = ev_update
+        .read()
+        .map(|update| (update.id, update))
+        .collect::<HashMap<_, _>>();
+    let replica = || RemoteReplica {
+        last_update: time.elapsed(),
+    };
+    let mut spawned = Vec::new();
+    let mut spawned_moving = Vec::new();
+    for (id, update) in latest {
+        let existing = registry
+            .get(id)
+            .and_then(|entity| Some((entity, replicas.get_mut(entity).ok()?)));
+        let Some((entity, (mut transform, mut target, mut last, linear, angular))) = existing
+        else {
+            let bundle = (
+                SpatialBundle::from_transform(update.transform),
+                TargetTransform(update.transform),
+                id,
+                replica(),
+            );
+            match update.velocity {
+                Some(velocity) => spawned_moving.push((bundle, velocity)),
+                None => spawned.push(bundle),
+            }
+            continue;
+        };
+        if smoothing.0 <= 0.0 {
+            *transform = update.transform;
+        }
+        target.0 = update.transform;
+        *last = replica();
+        match (update.velocity, linear, angular) {
+            (Some((new_linear, new_angular)), Some(mut linear), Some(mut angular)) => {
+                *linear = new_linear;
+                *angular = new_angular;
+            }
+            (Some(velocity), ..) => {
+                commands.entity(entity).insert(velocity);
+            }
+            (None, ..) => {}
+        }
+    }
+    if spawned.is_empty() && spawned_moving.is_empty() {
+        return;
+    }
+    commands.add(move |world: &mut World| {
+        let ids = spawned.iter().map(|(_, _, id, _)| *id).collect::<Vec<_>>();
+        let moving_ids = spawned_moving
+            .iter()
+            .map(|((_, _, id, _), _)| *id)
+            .collect::<Vec<_>>();
+        let entities = world.spawn_batch(spawned).collect::<Vec<_>>();
+        let moving_entities = world.spawn_batch(spawned_moving).collect::<Vec<_>>();
+        let mut registry = world.resource_mut::<ReplicaRegistry>();
+        registry.0.extend(ids.into_iter().zip(entities));
+        registry
+            .0
+            .extend(moving_ids.into_iter().zip(moving_entities));
+    });
+}
+
+pub fn unregister_replica(
+    trigger: Trigger<OnRemove, NetworkId>,
+    ids: Query<&NetworkId>,
+    mut registry: ResMut<ReplicaRegistry>,
+) {
+    if let Ok(id) = ids.get(trigger.entity()) {
+        // only if it's still ours, the id may have been taken over by a newer entity
+        if registry.0.get(id) == Some(&trigger.entity()) {
+            registry.0.remove(id);
+        }
+    }
+}
+
+/// Replicas that haven't been updated for this long are despawned, e.g. because the player
+/// owning them disconnected
+#[derive(Resource)]
+pub struct ReplicaTimeout(pub Duration);
+
+pub fn reap_stale_replicas(
+    mut commands: Commands,
+    replicas: Query<(Entity, &RemoteReplica)>,
+    time: Res<Time>,
+    timeout: Res<ReplicaTimeout>,
+) {
+    for (entity, replica) in replicas.iter() {
+        if time.elapsed().saturating_sub(replica.last_update) > timeout.0 {
+            info!("Despawning replica {entity}, it stopped receiving updates");
+            commands.entity(entity).despawn_recursive();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::system::RunSystemOnce;
+
+    use super::*;
+    use crate::{
+        testing::{app, connected_app, drain, queued_transforms, replica, update_until},
+        Outbox,
+    };
+
+    #[test]
+    fn received_transforms_move_replicas_smoothly() {
+        let mut app = app(Duration::from_millis(50));
+        let connection = app.world_mut().spawn_empty().id();
+        let id = NetworkId(7);
+        let send = |app: &mut App, x: f32| {
+            let transforms = vec![(id, Transform::from_xyz(x, 0.0, 0.0))];
+            app.world_mut().send_event(WebSocketMessageReceived {
+                entity: connection,
+                channel: None,
+                data: WireFormat::Bincode.encode(&transforms),
+                text: false,
+            });
+            app.update();
+        };
+        send(&mut app, 1.0);
+        app.update();
+        let entity = replica(&app, id).expect("no replica spawned");
+        let translation = |app: &App| app.world().get::<Transform>(entity).unwrap().translation;
+        assert_eq!(translation(&app).x, 1.0);
+
+        send(&mut app, 2.0);
+        assert_eq!(replica(&app, id), Some(entity), "spawned a second replica");
+        assert_eq!(
+            app.world()
+                .get::<TargetTransform>(entity)
+                .unwrap()
+                .0
+                .translation
+                .x,
+            2.0
+        );
+        let x = translation(&app).x;
+        assert!(
+            x > 1.0 && x < 2.0,
+            "jumped to {x} instead of easing towards 2"
+        );
+        for _ in 0..100 {
+            app.update();
+        }
+        assert!((translation(&app).x - 2.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn replicas_are_reaped_once_their_updates_cease() {
+        let mut app = app(Duration::from_millis(100));
+        app.insert_resource(ReplicaTimeout(Duration::from_secs(1)));
+        let id = NetworkId(3);
+        let update = |app: &mut App| {
+            app.world_mut().send_event(ReplicaUpdate {
+                id,
+                transform: Transform::default(),
+                velocity: None,
+            });
+            app.update();
+        };
+        update(&mut app);
+        app.update();
+        let entity = replica(&app, id).expect("no replica spawned");
+        // kept alive for longer than the timeout as long as updates keep coming
+        for _ in 0..20 {
+            update(&mut app);
+        }
+        assert!(app.world().get_entity(entity).is_some());
+
+        for _ in 0..12 {
+            app.update();
+        }
+        assert!(app.world().get_entity(entity).is_none());
+        assert_eq!(replica(&app, id), None);
+    }
+
+    #[test]
+    fn transform_hooks_convert_to_z_up_and_back() {
+        let (mut app, connection) = connected_app();
+        app.insert_resource(SendTransformHook(Box::new(|t| {
+            Transform::from_xyz(t.translation.x, -t.translation.z, t.translation.y)
+        })))
+        .insert_resource(RecvTransformHook(Box::new(|t| {
+            Transform::from_xyz(t.translation.x, t.translation.z, -t.translation.y)
+        })));
+        let id = NetworkId(42);
+        let world = app.world_mut();
+        world.spawn((Transform::from_xyz(1.0, 2.0, 3.0), id));
+        world.entity_mut(connection).insert(ReplicateTransforms);
+        let wire = update_until(&mut app, |app| {
+            drain::<WebSocketMessageReceived>(app)
+                .iter()
+                .filter_map(|received| decode_transforms(WireFormat::Bincode, &received.data))
+                .flatten()
+                .find(|&(sent, _)| sent == id)
+        });
+        assert_eq!(wire.1.translation, Vec3::new(1.0, -3.0, 2.0));
+        app.update();
+        let replica = replica(&app, id).expect("no replica spawned");
+        let target = app.world().get::<TargetTransform>(replica).unwrap();
+        assert_eq!(target.0.translation, Vec3::new(1.0, 2.0, 3.0));
+    }
+
+    #[test]
+    fn updates_for_one_network_id_share_one_replica() {
+        let mut app = app(Duration::from_millis(10));
+        let id = NetworkId(9);
+        let update = |x| ReplicaUpdate {
+            id,
+            transform: Transform::from_xyz(x, 0.0, 0.0),
+            velocity: None,
+        };
+        // twice in one frame, then again in later ones
+        app.world_mut().send_event(update(1.0));
+        app.world_mut().send_event(update(2.0));
+        app.update();
+        app.world_mut().send_event(update(3.0));
+        app.update();
+        app.update();
+        let mut replicas = app
+            .world_mut()
+            .query_filtered::<(Entity, &TargetTransform), With<RemoteReplica>>();
+        let replicas: Vec<_> = replicas
+            .iter(app.world())
+            .map(|(entity, target)| (entity, target.0.translation.x))
+            .collect();
+        assert_eq!(replicas.len(), 1);
+        assert_eq!(replica(&app, id), Some(replicas[0].0));
+        assert_eq!(replicas[0].1, 3.0);
+    }
+
+    #[test]
+    fn scoped_connections_only_replicate_their_entities() {
+        let mut app = app(Duration::from_millis(100));
+        let players: Vec<_> = (1..=3)
+            .map(|id| {
+                let transform = Transform::from_xyz(id as f32, 0.0, 0.0);
+                app.world_mut().spawn((transform, NetworkId(id))).id()
+            })
+            .collect();
+        let everyone = app
+            .world_mut()
+            .spawn((Outbox::default(), ReplicateTransforms))
+            .id();
+        let spectator = app
+            .world_mut()
+            .spawn((
+                Outbox::default(),
+                ReplicateTransforms,
+                ReplicationScope(vec![players[1]]),
+            ))
+            .id();
+        update_until(&mut app, |app| {
+            (!queued_transforms(app, everyone).is_empty()).then_some(())
+        });
+        let ids = |app: &App, connection| {
+            let (_, transforms) = queued_transforms(app, connection).remove(0);
+            transforms.into_iter().map(|(id, _)| id).collect::<Vec<_>>()
+        };
+        assert_eq!(
+            ids(&app, everyone),
+            [NetworkId(1), NetworkId(2), NetworkId(3)]
+        );
+        assert_eq!(ids(&app, spectator), [NetworkId(2)]);
+    }
+
+    #[test]
+    fn duplicate_network_ids_follow_the_policy() {
+        let batch = || {
+            vec![
+                (NetworkId(1), "a"),
+                (NetworkId(2), "b"),
+                (NetworkId(1), "c"),
+            ]
+        };
+        let dedup = |policy: DuplicateNetworkIds| -> Vec<_> {
+            policy
+                .dedup(batch(), |(id, _)| *id)
+                .into_iter()
+                .map(|(_, state)| state)
+                .collect()
+        };
+        assert_eq!(dedup(DuplicateNetworkIds::LastWins), ["b", "c"]);
+        assert_eq!(dedup(DuplicateNetworkIds::FirstWins), ["a", "b"]);
+        assert_eq!(dedup(DuplicateNetworkIds::Drop), ["b"]);
+    }
+
+    #[test]
+    fn new_dynamic_bodies_get_network_ids() {
+        let mut app = app(Duration::from_millis(10));
+        app.init_resource::<AutoNetworkIds>();
+        let dynamic = app.world_mut().spawn(RigidBody::Dynamic).id();
+        let fixed = app.world_mut().spawn(RigidBody::Static).id();
+        let tagged = app
+            .world_mut()
+            .spawn((RigidBody::Dynamic, NetworkId(3)))
+            .id();
+        let plain = app.world_mut().spawn(Transform::IDENTITY).id();
+        app.update();
+        let id = |entity| app.world().get::<NetworkId>(entity).copied();
+        assert_eq!(id(dynamic), Some(NetworkId(1 << 32)));
+        assert_eq!(id(fixed), None);
+        assert_eq!(id(tagged), Some(NetworkId(3)));
+        assert_eq!(id(plain), None);
+    }
+
+    #[test]
+    fn entity_references_round_trip_as_network_ids() {
+        #[derive(Serialize, Deserialize)]
+        struct Follow {
+            target: NetworkedEntity,
+        }
+
+        let mut sender = app(Duration::from_millis(10));
+        let player = sender.world_mut().spawn(NetworkId(9)).id();
+        let msg = sender
+            .world_mut()
+            .run_system_once(move |ids: Query<&NetworkId>| {
+                let follow = Follow {
+                    target: NetworkedEntity::from_local(player, &ids),
+                };
+                WireFormat::Bincode.encode(&follow)
+            });
+
+        let mut receiver = app(Duration::from_millis(10));
+        let follow: Follow = WireFormat::Bincode.decode(&msg).unwrap();
+        let registry = receiver.world().resource::<ReplicaRegistry>();
+        // nothing to refer to before the player's first update
+        assert_eq!(follow.target.to_local(registry), None);
+        receiver.world_mut().send_event(ReplicaUpdate {
+            id: NetworkId(9),
+            transform: Transform::IDENTITY,
+            velocity: None,
+        });
+        receiver.update();
+        let registry = receiver.world().resource::<ReplicaRegistry>();
+        let replica = replica(&receiver, NetworkId(9));
+        assert!(replica.is_some());
+        assert_eq!(follow.target.to_local(registry), replica);
+    }
+
+    #[test]
+    fn large_update_batches_are_applied_in_one_pass() {
+        let mut app = app(Duration::from_millis(10));
+        let send = |app: &mut App, x: f32| {
+            let updates = (0..10_000).map(|id| ReplicaUpdate {
+                id: NetworkId(id),
+                transform: Transform::from_xyz(x, id as f32, 0.0),
+                velocity: (id % 2 == 0).then_some((LinearVelocity(Vec3::X), AngularVelocity::ZERO)),
+            });
+            app.world_mut().send_event_batch(updates);
+            app.update();
+        };
+        send(&mut app, 1.0);
+        let mut replicas = app
+            .world_mut()
+            .query_filtered::<(&NetworkId, &TargetTransform), With<RemoteReplica>>();
+        assert_eq!(replicas.iter(app.world()).count(), 10_000);
+        assert_eq!(app.world().resource::<ReplicaRegistry>().0.len(), 10_000);
+        let moving = app
+            .world_mut()
+            .query_filtered::<(), (With<RemoteReplica>, With<LinearVelocity>)>()
+            .iter(app.world())
+            .count();
+        assert_eq!(moving, 5_000);
+
+        // the next batch lands on the same replicas
+        send(&mut app, 2.0);
+        assert_eq!(replicas.iter(app.world()).count(), 10_000);
+        assert!(replicas
+            .iter(app.world())
+            .all(|(id, target)| { target.0.translation == Vec3::new(2.0, id.0 as f32, 0.0) }));
+    }
+}
@@ -0,0 +1,744 @@
+//! What goes out and when: send intervals, [`send_info`], typed sends and [`WireTap`]s.
+
+use std::{sync::Arc, time::Duration};
+
+use bevy::{
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+
+use crate::{
+    BinaryDiff, Channel, Closing, Compression, ConnectionState, MessageSendOutcome, Multiplexed,
+    NetworkId, Outbox, OutboxBudgetExceeded, OutboxMemoryBudget, RemoteReplica,
+    ReplicateTransforms, ReplicationScope, Rtt, SendOutcome, SendTransformHook, SerializeBuffer,
+    WireFormat, GAME_STATE_CHANNEL,
+};
+
+/// The send interval from
+/// [`WebSocketPlugin::with_send_interval`](crate::WebSocketPlugin::with_send_interval). Connections
+/// set up by the plugin start their [`AdaptiveSendInterval`] at it and go at their own pace from
+/// there, the timer here only paces connections without one.
+#[derive(Resource)]
+pub struct SendMessageConfig {
+    pub(crate) timer: Timer,
+}
+
+/// Bounds and trigger for [`AdaptiveSendInterval`]
+#[derive(Resource)]
+pub struct AdaptiveSendConfig {
+    pub min_interval: Duration,
+    pub max_interval: Duration,
+    /// An [`Rtt`] above this counts as congestion
+    pub congested_rtt: Duration,
+}
+
+impl Default for AdaptiveSendConfig {
+    fn default() -> Self {
+        Self {
+            min_interval: Duration::from_millis(100),
+            max_interval: Duration::from_secs(5),
+            congested_rtt: Duration::from_millis(250),
+        }
+    }
+}
+
+/// Send to this connection at its own pace instead of [`SendMessageConfig`]'s.
+///
+/// The interval doubles whenever the [`Rtt`] says the connection is congested and shrinks
+/// slowly again while it's healthy, so slow clients aren't flooded.
+#[derive(Component)]
+pub struct AdaptiveSendInterval {
+    pub(crate) interval: Duration,
+    since_last_send: Duration,
+    /// Whether the senders should send this frame
+    due: bool,
+}
+
+impl AdaptiveSendInterval {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            since_last_send: Duration::ZERO,
+            due: false,
+        }
+    }
+
+    /// How often this connection is sent to right now
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Time left until this connection is sent to next
+    pub fn until_next_send(&self) -> Duration {
+        self.interval.saturating_sub(self.since_last_send)
+    }
+}
+
+pub fn tick_adaptive_send_intervals(mut q: Query<&mut AdaptiveSendInterval>, time: Res<Time>) {
+    for mut adaptive in &mut q {
+        adaptive.since_last_send += time.delta();
+        adaptive.due = adaptive.since_last_send >= adaptive.interval;
+        if adaptive.due {
+            adaptive.since_last_send = Duration::ZERO;
+        }
+    }
+}
+
+pub fn adapt_send_intervals(
+    mut q: Query<(Entity, &Rtt, &mut AdaptiveSendInterval), Changed<Rtt>>,
+    config: Res<AdaptiveSendConfig>,
+) {
+    for (entity, rtt, mut adaptive) in &mut q {
+        let interval = if rtt.0 > config.congested_rtt {
+            adaptive.interval * 2
+        } else {
+            adaptive.interval * 7 / 8
+        };
+        let interval = interval.clamp(config.min_interval, config.max_interval);
+        if interval != adaptive.interval {
+            adaptive.interval = interval;
+            debug!("Sending to {entity} every {interval:?} (rtt {:?})", rtt.0);
+        }
+    }
+}
+
+impl SendMessageConfig {
+    /// How often data is sent to connections without an [`AdaptiveSendInterval`], and what
+    /// new connections start their adaptive interval at
+    pub fn interval(&self) -> Duration {
+        self.timer.duration()
+    }
+
+    /// Time left until the next send to connections without an [`AdaptiveSendInterval`] fires,
+    /// e.g. to show a countdown in the UI. See [`AdaptiveSendInterval::until_next_send`] for
+    /// the others.
+    pub fn until_next_send(&self) -> Duration {
+        self.timer.remaining()
+    }
+
+    /// Whether a connection should send this frame, at its [`AdaptiveSendInterval`] if it has one
+    pub fn is_due(&self, adaptive: Option<&AdaptiveSendInterval>) -> bool {
+        adaptive.map_or(self.timer.finished(), |adaptive| adaptive.due)
+    }
+}
+
+#[allow(clippy::type_complexity, clippy::too_many_arguments)]
+pub fn send_info(
+    // replicas would otherwise be sent back and replicated again
+    some_data: Query<(Entity, &Transform, Option<&NetworkId>), Without<RemoteReplica>>,
+    time: Res<Time>,
+    mut entities_with_client: Query<
+        (
+            Entity,
+            &mut Outbox,
+            Option<&mut BinaryDiff>,
+            Option<&Compression>,
+            Option<&WireFormat>,
+            Has<Multiplexed>,
+            Option<&AdaptiveSendInterval>,
+            Option<&ReplicationScope>,
+            Option<&mut SerializeBuffer>,
+            Option<&mut DeltaTransforms>,
+        ),
+        (With<ReplicateTransforms>, Without<Closing>),
+    >,
+    mut config: ResMut<SendMessageConfig>,
+    hook: Res<SendTransformHook>,
+    mut budget: ResMut<OutboxMemoryBudget>,
+    mut ev_exceeded: EventWriter<OutboxBudgetExceeded>,
+    tap: Option<Res<WireTap>>,
+    mut ev_full: EventReader<SendFullSnapshot>,
+) {
+    config.timer.tick(time.delta());
+    let full = ev_full.read().map(|ev| ev.entity).collect::<HashSet<_>>();
+    for (
+        entity,
+        mut outbox,
+        mut diff,
+        compression,
+        format,
+        multiplexed,
+        adaptive,
+        scope,
+        buffer,
+        delta,
+    ) in entities_with_client.iter_mut()
+    {
+        let full = full.contains(&entity);
+        // only send messages once every second (or as adapted), so we don't spam the server
+        let due = config.is_due(adaptive);
+        if !due && !full {
+            continue;
+        }
+        info!("Time to send data again...");
+        if full {
+            if let Some(diff) = diff.as_mut() {
+                // nothing to patch against, so the whole snapshot goes out
+                diff.last_sent = None;
+            }
+        }
+        let tagged =
+            |(entity, transform, id)| (NetworkId::or_entity(id, entity), (hook.0)(transform));
+        let transforms = &match scope {
+            Some(scope) => scope
+                .0
+                .iter()
+                .filter_map(|&scoped| some_data.get(scoped).ok())
+                .map(tagged)
+                .collect::<Vec<_>>(),
+            None => some_data.iter().map(tagged).collect::<Vec<_>>(),
+        };
+        let format = format.copied().unwrap_or_default();
+        let changed = match delta {
+            Some(mut delta) => {
+                let sent = transforms.iter().copied().collect::<HashMap<_, _>>();
+                let last_sent = std::mem::replace(&mut delta.last_sent, sent);
+                // only a full send tells the receiver about entities that went away
+                let gone = last_sent.keys().any(|id| !delta.last_sent.contains_key(id));
+                if full || gone {
+                    None
+                } else {
+                    let changed = transforms
+                        .iter()
+                        .filter(|(id, transform)| last_sent.get(id) != Some(transform))
+                        .copied()
+                        .collect::<Vec<_>>();
+                    if changed.is_empty() {
+                        continue;
+                    }
+                    Some(changed)
+                }
+            }
+            None => None,
+        };
+        info!("Sending data: {transforms:?}");
+        let encode = |buf: &mut Vec<u8>| match &changed {
+            Some(changed) => {
+                buf.extend_from_slice(&TRANSFORM_DELTA_TAG);
+                format.encode_into(changed, buf);
+            }
+            None => format.encode_into(transforms, buf),
+        };
+        let msg = match buffer {
+            Some(mut buffer) => {
+                buffer.0.clear();
+                encode(&mut buffer.0);
+                buffer.0.clone()
+            }
+            None => {
+                let mut msg = Vec::new();
+                encode(&mut msg);
+                msg
+            }
+        };
+        let channel = multiplexed.then_some(GAME_STATE_CHANNEL);
+        enqueue(
+            entity,
+            channel,
+            msg,
+            &mut outbox,
+            diff,
+            compression,
+            &mut budget,
+            &mut ev_exceeded,
+            tap.as_deref(),
+        );
+    }
+}
+
+/// Only send the transforms that are new or changed since the last send on this connection,
+/// behind [`TRANSFORM_DELTA_TAG`], and nothing at all if none are. Whenever entities went
+/// away, or for a [`SendFullSnapshot`], everything is sent as usual. Opt-in, receivers need
+/// to understand the delta messages, see [`decode_transforms`].
+#[derive(Component, Default)]
+pub struct DeltaTransforms {
+    last_sent: HashMap<NetworkId, Transform>,
+}
+
+/// Marks messages carrying the changed transforms of [`DeltaTransforms`]
+pub const TRANSFORM_DELTA_TAG: [u8; 4] = [0xff, b't', b'd', b'l'];
+
+/// Decode what `send_info` sent, whole or as a delta, into transforms by [`NetworkId`]
+pub fn decode_transforms(format: WireFormat, data: &[u8]) -> Option<Vec<(NetworkId, Transform)>> {
+    // both are the same pairs, the tag only says whether the others are left out
+    format.decode(data.strip_prefix(&TRANSFORM_DELTA_TAG[..]).unwrap_or(data))
+}
+
+/// Send the whole state to one connection right away, regardless of [`SendMessageConfig`]
+/// and without diffing, e.g. when the peer asks to resync
+#[derive(Event)]
+pub struct SendFullSnapshot {
+    pub entity: Entity,
+}
+
+/// Queue data for exactly one connection, e.g. to reply to a single client
+#[derive(Event)]
+pub struct SendTo {
+    pub entity: Entity,
+    pub data: Vec<u8>,
+}
+
+/// Queue a text message for one connection, e.g. for servers that speak JSON text frames.
+///
+/// It's sent as is, without [`Compression`], [`BinaryDiff`] or a [`Multiplexed`] channel,
+/// and received with [`WebSocketMessageReceived::text`](crate::WebSocketMessageReceived::text) set.
+#[derive(Event)]
+pub struct SendText {
+    pub entity: Entity,
+    pub text: String,
+}
+
+pub fn send_text(
+    mut ev_send: EventReader<SendText>,
+    mut outboxes: Query<&mut Outbox, Without<Closing>>,
+    mut budget: ResMut<OutboxMemoryBudget>,
+    mut ev_exceeded: EventWriter<OutboxBudgetExceeded>,
+) {
+    for SendText { entity, text } in ev_send.read() {
+        let Ok(mut outbox) = outboxes.get_mut(*entity) else {
+            warn!("Not sending text to {entity}: it has no open connection");
+            continue;
+        };
+        if let Err(text) = outbox.push_text(text.clone(), &mut budget) {
+            ev_exceeded.send(OutboxBudgetExceeded {
+                entity: *entity,
+                refused_bytes: text.len(),
+            });
+        }
+    }
+}
+
+/// What [`SendTo`] does with data for a connection that's closing or already closed
+#[derive(Resource, Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub enum SendWhileClosed {
+    /// Report it as a [`SendOutcome::Failed`]
+    #[default]
+    Reject,
+    /// Drop it without a word
+    Drop,
+    /// Queue it anyway if the connection kept its [`Outbox`], see
+    /// [`ResendQueuedOnReconnect`](crate::ResendQueuedOnReconnect)
+    Queue,
+}
+
+#[allow(clippy::type_complexity, clippy::too_many_arguments)]
+pub fn send_to(
+    mut ev_send: EventReader<SendTo>,
+    mut outboxes: Query<(
+        Option<&mut Outbox>,
+        Option<&mut BinaryDiff>,
+        Option<&Compression>,
+        Option<&ConnectionState>,
+    )>,
+    policy: Res<SendWhileClosed>,
+    mut budget: ResMut<OutboxMemoryBudget>,
+    mut ev_exceeded: EventWriter<OutboxBudgetExceeded>,
+    mut ev_outcome: EventWriter<MessageSendOutcome>,
+    tap: Option<Res<WireTap>>,
+) {
+    for SendTo { entity, data } in ev_send.read() {
+        let Ok((outbox, diff, compression, state)) = outboxes.get_mut(*entity) else {
+            warn!("Not sending to {entity}: it isn't a connection");
+            continue;
+        };
+        let closed = matches!(
+            state,
+            Some(
+                ConnectionState::Closing
+                    | ConnectionState::Disconnected
+                    | ConnectionState::AttemptFailed
+                    | ConnectionState::Failed
+            )
+        );
+        let outbox = match (outbox, closed, *policy) {
+            (_, true, SendWhileClosed::Drop) => continue,
+            (Some(outbox), false, _) | (Some(outbox), true, SendWhileClosed::Queue) => Some(outbox),
+            _ => None,
+        };
+        match outbox {
+            Some(mut outbox) => {
+                enqueue(
+                    *entity,
+                    None,
+                    data.clone(),
+                    &mut outbox,
+                    diff,
+                    compression,
+                    &mut budget,
+                    &mut ev_exceeded,
+                    tap.as_deref(),
+                );
+            }
+            None => {
+                let state = state.map_or("not connected".to_owned(), |state| format!("{state:?}"));
+                ev_outcome.send(MessageSendOutcome {
+                    entity: *entity,
+                    bytes: data.len(),
+                    outcome: SendOutcome::Failed(format!("the connection is {state}")),
+                });
+            }
+        }
+    }
+}
+
+/// Where in the pipeline [`WireTap`] saw some bytes
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum WireStage {
+    /// Serialized by the caller, before anything else touched it
+    Encoded,
+    /// After [`BinaryDiff`] turned it into a full message or a patch
+    Diffed,
+    /// After [`Compression`]
+    Compressed,
+    /// The exact payload handed to the websocket, with the [`Channel`] prefix if multiplexed.
+    /// TLS encrypts below that, so use a TLS keylog to look at the encrypted bytes.
+    Outgoing,
+    /// The payload of a received frame, as it came off the socket
+    Incoming,
+    /// After undoing channel, compression and diff, right before it's deserialized
+    Decoded,
+}
+
+/// Observe the bytes of every message at each stage of sending and receiving, e.g. to diff
+/// what you think you're sending against what a picky server gets. Observe-only, the
+/// callback can't change anything.
+///
+/// Covers messages queued with [`enqueue`], heartbeats are only seen as they come in.
+#[derive(Resource, Clone)]
+pub struct WireTap(pub(crate) Arc<TapFn>);
+
+pub type TapFn = dyn Fn(Entity, WireStage, &[u8]) + Send + Sync;
+
+impl WireTap {
+    pub fn new(tap: impl Fn(Entity, WireStage, &[u8]) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(tap))
+    }
+}
+
+/// Queue `msg` on a connection, diffing and compressing it if the connection opted into that
+#[allow(clippy::too_many_arguments)]
+pub fn enqueue(
+    entity: Entity,
+    channel: Option<Channel>,
+    msg: Vec<u8>,
+    outbox: &mut Outbox,
+    diff: Option<Mut<BinaryDiff>>,
+    compression: Option<&Compression>,
+    budget: &mut OutboxMemoryBudget,
+    ev_exceeded: &mut EventWriter<OutboxBudgetExceeded>,
+    tap: Option<&WireTap>,
+) {
+    let tap = |stage, data: &[u8]| {
+        if let Some(tap) = tap {
+            (tap.0)(entity, stage, data);
+        }
+    };
+    tap(WireStage::Encoded, &msg);
+    let (msg, mut diff) = match diff {
+        Some(mut diff) => {
+            let msg = diff.encode(msg);
+            tap(WireStage::Diffed, &msg);
+            (msg, Some(diff))
+        }
+        None => (msg, None),
+    };
+    let mut msg = match compression {
+        Some(compression) => {
+            let msg = compression.encode(msg);
+            tap(WireStage::Compressed, &msg);
+            msg
+        }
+        None => msg,
+    };
+    if let Some(channel) = channel {
+        msg.insert(0, channel);
+    }
+    tap(WireStage::Outgoing, &msg);
+    if let Err(msg) = outbox.push(msg, budget) {
+        if let Some(diff) = diff.as_mut() {
+            // the peer will never see this message, so the next one can't be a patch against it
+            diff.last_sent = None;
+        }
+        ev_exceeded.send(OutboxBudgetExceeded {
+            entity,
+            refused_bytes: msg.len(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, sync::Mutex};
+
+    use bevy::time::TimeUpdateStrategy;
+    use tungstenite::Message;
+
+    use super::*;
+    use crate::{
+        testing::{
+            app, connect, connected_app, drain, queued_transforms, read_raw_frame, send_outcome,
+            update_until,
+        },
+        CloseCode, ConnectionClosed, ConnectionOpened, Disconnect, LazyConnect, LocalEchoServer,
+        WebSocketConnectionEvents, WebSocketMessageReceived, WebSocketPlugin,
+    };
+
+    #[test]
+    fn messages_only_reach_the_connection_they_are_sent_to() {
+        let (mut app, _) = connected_app();
+        let second = connect(&mut app);
+        connect(&mut app);
+        app.world_mut().send_event(SendTo {
+            entity: second,
+            data: b"only you".to_vec(),
+        });
+        let mut received = Vec::new();
+        for _ in 0..50 {
+            app.update();
+            received.extend(drain::<WebSocketMessageReceived>(&mut app));
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        let from: Vec<_> = received.iter().map(|received| received.entity).collect();
+        assert_eq!(from, [second]);
+    }
+
+    #[test]
+    fn time_until_the_next_send_counts_down() {
+        let mut app = app(Duration::from_millis(100));
+        let remaining = |app: &mut App| {
+            app.update();
+            app.world()
+                .resource::<SendMessageConfig>()
+                .until_next_send()
+        };
+        let interval = app.world().resource::<SendMessageConfig>().interval();
+        assert_eq!(interval, Duration::from_secs(1));
+        let mut last = remaining(&mut app);
+        for _ in 0..5 {
+            let now = remaining(&mut app);
+            assert_eq!(last - now, Duration::from_millis(100));
+            last = now;
+        }
+        // starts over once it fired
+        while last > Duration::from_millis(100) {
+            last = remaining(&mut app);
+        }
+        assert_eq!(remaining(&mut app), interval);
+    }
+
+    #[test]
+    fn time_until_a_connections_next_send_counts_down_at_its_own_pace() {
+        let mut app = app(Duration::from_millis(100));
+        let connection = app
+            .world_mut()
+            .spawn(AdaptiveSendInterval::new(Duration::from_millis(300)))
+            .id();
+        let mut remaining = || {
+            app.update();
+            let adaptive = app.world().get::<AdaptiveSendInterval>(connection).unwrap();
+            adaptive.until_next_send().as_millis()
+        };
+        let countdown: Vec<_> = std::iter::repeat_with(&mut remaining).take(6).collect();
+        // the first update doesn't advance the clock
+        assert_eq!(countdown, [300, 200, 100, 300, 200, 100]);
+    }
+
+    #[test]
+    fn full_snapshots_go_out_on_demand_even_without_changes() {
+        let mut app = app(Duration::from_millis(100));
+        // never connected, so nothing takes the messages out of the outbox
+        let connection = app
+            .world_mut()
+            .spawn((
+                Outbox::default(),
+                ReplicateTransforms,
+                DeltaTransforms::default(),
+            ))
+            .id();
+        app.world_mut().spawn((Transform::IDENTITY, NetworkId(1)));
+        let queued = |app: &App| queued_transforms(app, connection);
+        update_until(&mut app, |app| (queued(app).len() == 1).then_some(()));
+        // nothing changed, so the next sends are skipped
+        for _ in 0..25 {
+            app.update();
+        }
+        assert_eq!(queued(&app).len(), 1);
+
+        app.world_mut()
+            .send_event(SendFullSnapshot { entity: connection });
+        app.update();
+        let queued = queued(&app);
+        assert_eq!(queued.len(), 2);
+        assert_eq!(
+            queued[1],
+            (false, vec![(NetworkId(1), Transform::IDENTITY)])
+        );
+    }
+
+    #[test]
+    fn connections_start_at_the_configured_send_interval() {
+        let mut app = app(Duration::from_millis(10));
+        // lazy, so nothing needs to answer
+        app.insert_resource(LazyConnect(true))
+            .insert_resource(SendMessageConfig {
+                timer: Timer::new(Duration::from_millis(300), TimerMode::Repeating),
+            });
+        app.world_mut()
+            .send_event(WebSocketConnectionEvents::SetupConnection(None));
+        app.update();
+        let mut adaptive = app.world_mut().query::<&AdaptiveSendInterval>();
+        let adaptive = adaptive.single(app.world());
+        assert_eq!(adaptive.interval(), Duration::from_millis(300));
+    }
+
+    #[test]
+    fn transforms_go_out_at_the_plugins_send_interval() {
+        let server = LocalEchoServer::start().unwrap();
+        let mut app = App::new();
+        app.add_plugins((
+            MinimalPlugins,
+            WebSocketPlugin::default()
+                .with_url(server.url())
+                .with_send_interval(Duration::from_millis(300))
+                // no round trip times to adapt the interval to
+                .with_heartbeat_interval(Duration::from_secs(600)),
+        ))
+        .insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_millis(
+            100,
+        )))
+        .insert_resource(server);
+        let connection = connect(&mut app);
+        let sent = Arc::new(Mutex::new(0));
+        app.insert_resource(WireTap::new({
+            let sent = sent.clone();
+            move |_, stage, _| {
+                if stage == WireStage::Encoded {
+                    *sent.lock().unwrap() += 1;
+                }
+            }
+        }));
+        app.world_mut()
+            .entity_mut(connection)
+            .insert(ReplicateTransforms);
+        app.world_mut().spawn(Transform::IDENTITY);
+        let mut frames = Vec::new();
+        for frame in 0..12 {
+            let before = *sent.lock().unwrap();
+            app.update();
+            if *sent.lock().unwrap() > before {
+                frames.push(frame);
+            }
+        }
+        assert_eq!(frames.len(), 4, "sent in frames {frames:?}");
+        assert!(
+            frames.windows(2).all(|pair| pair[1] - pair[0] == 3),
+            "{frames:?}"
+        );
+    }
+
+    #[test]
+    fn send_intervals_back_off_under_congestion_and_recover() {
+        let mut app = app(Duration::from_millis(10));
+        let connection = app
+            .world_mut()
+            .spawn(AdaptiveSendInterval::new(Duration::from_millis(200)))
+            .id();
+        let mut measure = |rtt| {
+            app.world_mut().entity_mut(connection).insert(Rtt(rtt));
+            app.update();
+            let adaptive = app.world().get::<AdaptiveSendInterval>(connection).unwrap();
+            adaptive.interval().as_millis()
+        };
+        let congested = Duration::from_millis(400);
+        assert_eq!(measure(congested), 400);
+        assert_eq!(measure(congested), 800);
+        for _ in 0..10 {
+            measure(congested);
+        }
+        assert_eq!(measure(congested), 5000, "not capped at the maximum");
+
+        let healthy = Duration::from_millis(20);
+        assert_eq!(measure(healthy), 4375);
+        for _ in 0..50 {
+            measure(healthy);
+        }
+        assert_eq!(measure(healthy), 100, "not floored at the minimum");
+    }
+
+    #[test]
+    fn wire_taps_see_the_final_frame_bytes() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}/", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut socket = tungstenite::accept(stream.try_clone().unwrap()).unwrap();
+            socket
+                .send(Message::binary(b"from the server".to_vec()))
+                .unwrap();
+            std::iter::from_fn(|| read_raw_frame(&mut stream))
+                .find(|(_, opcode, _)| *opcode == 2)
+                .map(|(_, _, payload)| payload)
+        });
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let tap = {
+            let seen = seen.clone();
+            WireTap::new(move |_, stage, data| seen.lock().unwrap().push((stage, data.to_vec())))
+        };
+        let mut app = app(Duration::from_millis(10));
+        app.insert_resource(tap);
+        app.world_mut()
+            .send_event(WebSocketConnectionEvents::SetupConnection(Some(url)));
+        let connection = update_until(&mut app, |app| drain::<ConnectionOpened>(app).pop()).entity;
+        app.world_mut()
+            .entity_mut(connection)
+            .insert(Compression::default());
+        let msg = vec![b'x'; 4096];
+        app.world_mut().send_event(SendTo {
+            entity: connection,
+            data: msg.clone(),
+        });
+        update_until(&mut app, |_| server.is_finished().then_some(()));
+        let written = server.join().unwrap().expect("no binary frame");
+        let seen = seen.lock().unwrap();
+        let stage = |wanted| {
+            seen.iter()
+                .find(|(stage, _)| *stage == wanted)
+                .map(|(_, data)| data.clone())
+        };
+        assert_eq!(stage(WireStage::Encoded), Some(msg));
+        assert_eq!(stage(WireStage::Outgoing), Some(written.clone()));
+        assert!(stage(WireStage::Compressed).is_some_and(|c| c == written && c.len() < 4096));
+        assert_eq!(
+            stage(WireStage::Incoming),
+            Some(b"from the server".to_vec())
+        );
+    }
+
+    #[test]
+    fn sends_to_closing_connections_are_rejected() {
+        let (mut app, connection) = connected_app();
+        app.world_mut().send_event(Disconnect {
+            entity: connection,
+            code: CloseCode::Normal,
+            reason: String::new(),
+        });
+        app.update();
+        assert_eq!(
+            app.world().get::<ConnectionState>(connection),
+            Some(&ConnectionState::Closing)
+        );
+        match send_outcome(&mut app, connection) {
+            SendOutcome::Failed(e) => assert!(e.contains("Closing"), "{e}"),
+            outcome => panic!("{outcome:?}"),
+        }
+
+        update_until(&mut app, |app| drain::<ConnectionClosed>(app).pop());
+        app.insert_resource(SendWhileClosed::Drop);
+        app.world_mut().send_event(SendTo {
+            entity: connection,
+            data: b"hello".to_vec(),
+        });
+        app.update();
+        assert!(drain::<MessageSendOutcome>(&mut app).is_empty());
+    }
+}
@@ -0,0 +1,471 @@
+//! Accepting connections with a [`WebSocketServer`], and the [`LocalEchoServer`] for tests.
+
+use std::{
+    io::{ErrorKind, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::Arc,
+    time::Duration,
+};
+
+use bevy::{
+    ecs::world::CommandQueue,
+    prelude::*,
+    tasks::{block_on, futures_lite::future, AsyncComputeTaskPool, Task},
+};
+use tungstenite::{
+    handshake::{server, HandshakeError},
+    http::{Response, StatusCode},
+    stream::MaybeTlsStream,
+    Message, WebSocket,
+};
+
+use crate::{
+    ActiveSizeLimits, ConnectionOpened, ConnectionSetupError, ConnectionState, IsSecure, Outbox,
+    SizeLimits, SocketTimeouts, WebSocketClient,
+};
+
+/// Accepts websocket connections from other peers, native only.
+///
+/// Every accepted peer becomes an entity with a [`WebSocketClient`] and [`Outbox`],
+/// just like outgoing connections.
+#[derive(Resource)]
+pub struct WebSocketServer {
+    listener: TcpListener,
+}
+
+impl WebSocketServer {
+    pub fn bind(addr: impl ToSocketAddrs) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(Self { listener })
+    }
+}
+
+/// Inspects each upgrade request before the handshake completes, e.g. to add response
+/// headers or turn the peer away with an [`server::ErrorResponse`].
+///
+/// tungstenite fills in `Sec-WebSocket-Accept` before this runs, don't remove it.
+#[derive(Resource, Clone)]
+pub struct HandshakeHook(Arc<HandshakeFn>);
+
+pub type HandshakeFn = dyn Fn(&server::Request, server::Response) -> Result<server::Response, server::ErrorResponse>
+    + Send
+    + Sync;
+
+impl HandshakeHook {
+    pub fn new(
+        hook: impl Fn(
+                &server::Request,
+                server::Response,
+            ) -> Result<server::Response, server::ErrorResponse>
+            + Send
+            + Sync
+            + 'static,
+    ) -> Self {
+        Self(Arc::new(hook))
+    }
+}
+
+impl Default for HandshakeHook {
+    #[allow(clippy::result_large_err)]
+    fn default() -> Self {
+        Self(Arc::new(|_, response| Ok(response)))
+    }
+}
+
+/// Decides whether a peer may connect, from its upgrade request (e.g. a token or cookie).
+///
+/// `Err(status)` turns the peer away with that HTTP status before the connection is
+/// set up, and reports a [`ConnectionRejected`]. Runs before the [`HandshakeHook`].
+#[derive(Resource, Clone)]
+pub struct ConnectionAuthorizer(Arc<AuthorizeFn>);
+
+pub type AuthorizeFn = dyn Fn(&server::Request) -> Result<(), u16> + Send + Sync;
+
+impl ConnectionAuthorizer {
+    pub fn new(
+        authorize: impl Fn(&server::Request) -> Result<(), u16> + Send + Sync + 'static,
+    ) -> Self {
+        Self(Arc::new(authorize))
+    }
+}
+
+/// A peer's upgrade request was refused, by the [`ConnectionAuthorizer`] or
+/// [`HandshakeHook`], or because it wasn't a valid upgrade request
+#[derive(Event, Clone)]
+pub struct ConnectionRejected {
+    pub peer: SocketAddr,
+    /// The HTTP status the peer got
+    pub status: u16,
+    pub reason: String,
+}
+
+/// How long a peer gets to send its upgrade request
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Component)]
+pub struct ServerHandshakeTask(
+    Task<Result<CommandQueue, ConnectionSetupError>>,
+    /// Who we're doing the handshake with
+    SocketAddr,
+);
+
+/// Do the server side of the handshake on a freshly accepted stream
+#[allow(clippy::result_large_err)]
+fn accept(
+    stream: TcpStream,
+    hook: &HandshakeHook,
+    authorizer: Option<&ConnectionAuthorizer>,
+    timeouts: &SocketTimeouts,
+    limits: &SizeLimits,
+) -> Result<WebSocket<MaybeTlsStream<TcpStream>>, ConnectionSetupError> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(
+        timeouts
+            .read
+            .map_or(HANDSHAKE_TIMEOUT, |read| read.min(HANDSHAKE_TIMEOUT)),
+    ))?;
+    stream.set_write_timeout(timeouts.write)?;
+    let mut reply = stream.try_clone()?;
+    let callback = |request: &server::Request, response| {
+        if let Some(Err(status)) = authorizer.map(|authorizer| (authorizer.0)(request)) {
+            let mut error = server::ErrorResponse::new(None);
+            *error.status_mut() = StatusCode::from_u16(status).unwrap_or(StatusCode::FORBIDDEN);
+            return Err(error);
+        }
+        (hook.0)(request, response)
+    };
+    let stream = MaybeTlsStream::Plain(stream);
+    let socket = match tungstenite::accept_hdr_with_config(stream, callback, Some(limits.config()))
+    {
+        Ok(socket) => socket,
+        // the peer was turned away, tungstenite already sent our response
+        Err(HandshakeError::Failure(e @ tungstenite::Error::Http(_))) => return Err(e.into()),
+        Err(HandshakeError::Failure(e)) => {
+            // not a valid upgrade request, tungstenite just drops those
+            let _ = reply.write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n");
+            warn!("Invalid upgrade request: {e}");
+            let mut response = Response::new(None);
+            *response.status_mut() = StatusCode::BAD_REQUEST;
+            return Err(tungstenite::Error::Http(response).into());
+        }
+        Err(HandshakeError::Interrupted(_)) => {
+            return Err(std::io::Error::from(ErrorKind::WouldBlock).into())
+        }
+    };
+    timeouts.apply(&reply)?;
+    reply.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+pub fn accept_connections(
+    mut commands: Commands,
+    server: Res<WebSocketServer>,
+    hook: Option<Res<HandshakeHook>>,
+    authorizer: Option<Res<ConnectionAuthorizer>>,
+    timeouts: Option<Res<SocketTimeouts>>,
+    limits: Option<Res<SizeLimits>>,
+) {
+    loop {
+        let (stream, addr) = match server.listener.accept() {
+            Ok(accepted) => accepted,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+            Err(e) => {
+                warn!("Could not accept a connection: {e}");
+                break;
+            }
+        };
+        info!("Accepted a connection from {addr}");
+        let hook = hook.as_deref().cloned().unwrap_or_default();
+        let authorizer = authorizer.as_deref().cloned();
+        let timeouts = timeouts.as_deref().cloned().unwrap_or_default();
+        let limits = limits.as_deref().copied().unwrap_or_default();
+        let entity = commands.spawn(Name::new(format!("peer {addr}"))).id();
+        let task = AsyncComputeTaskPool::get().spawn(async move {
+            let socket = accept(stream, &hook, authorizer.as_ref(), &timeouts, &limits)?;
+            let mut command_queue = CommandQueue::default();
+            command_queue.push(move |world: &mut World| {
+                let Some(mut entity_mut) = world.get_entity_mut(entity) else {
+                    return;
+                };
+                // there's no handshake response on this side
+                let client = WebSocketClient::native((socket, Response::default()));
+                let opened = ConnectionOpened::of(entity, &client, false);
+                entity_mut
+                    .insert((
+                        ActiveSizeLimits(opened.limits),
+                        client,
+                        IsSecure(false),
+                        Outbox::default(),
+                        ConnectionState::Connected,
+                    ))
+                    .remove::<ServerHandshakeTask>();
+                world.send_event(opened.clone());
+                world.trigger_targets(opened, entity);
+            });
+            Ok(command_queue)
+        });
+        commands
+            .entity(entity)
+            .insert(ServerHandshakeTask(task, addr));
+    }
+}
+
+pub fn handle_server_handshakes(
+    mut commands: Commands,
+    mut tasks: Query<(Entity, &mut ServerHandshakeTask)>,
+    mut ev_rejected: EventWriter<ConnectionRejected>,
+) {
+    for (entity, mut task) in &mut tasks {
+        let Some(result) = block_on(future::poll_once(&mut task.0)) else {
+            continue;
+        };
+        match result {
+            Ok(mut command_queue) => commands.append(&mut command_queue),
+            Err(e) => {
+                if let ConnectionSetupError::WebSocket(tungstenite::Error::Http(response)) = &e {
+                    ev_rejected.send(ConnectionRejected {
+                        peer: task.1,
+                        status: response.status().as_u16(),
+                        reason: e.to_string(),
+                    });
+                } else {
+                    warn!("Handshake with {} failed: {e}", task.1);
+                }
+                commands.entity(entity).despawn_recursive();
+            }
+        }
+    }
+}
+
+pub fn log_rejected_connections(mut ev_rejected: EventReader<ConnectionRejected>) {
+    for ConnectionRejected {
+        peer,
+        status,
+        reason,
+    } in ev_rejected.read()
+    {
+        warn!("Rejected connection from {peer} with {status}: {reason}");
+    }
+}
+
+/// A plain `ws://` echo server on an ephemeral loopback port, running on background threads,
+/// so the whole native path can be exercised without external services. Native only.
+///
+/// Dropping it stops the server and waits for its threads.
+#[derive(Resource)]
+pub struct LocalEchoServer {
+    url: String,
+    stop: Arc<std::sync::atomic::AtomicBool>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl LocalEchoServer {
+    /// How often the threads check whether they should stop
+    const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+    pub fn start() -> std::io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        listener.set_nonblocking(true)?;
+        let url = format!("ws://{}/", listener.local_addr()?);
+        let stop = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let thread = std::thread::spawn({
+            let stop = stop.clone();
+            move || Self::accept_loop(listener, &stop)
+        });
+        Ok(Self {
+            url,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    fn accept_loop(listener: TcpListener, stop: &Arc<std::sync::atomic::AtomicBool>) {
+        let mut connections = Vec::new();
+        while !stop.load(std::sync::atomic::Ordering::Relaxed) {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    let stop = stop.clone();
+                    connections.push(std::thread::spawn(move || Self::echo(stream, &stop)));
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    std::thread::sleep(Self::POLL_INTERVAL)
+                }
+                Err(e) => {
+                    warn!("Local echo server stopped accepting: {e}");
+                    break;
+                }
+            }
+            connections.retain(|connection| !connection.is_finished());
+        }
+        for connection in connections {
+            let _ = connection.join();
+        }
+    }
+
+    fn echo(stream: TcpStream, stop: &std::sync::atomic::AtomicBool) {
+        #[allow(clippy::result_large_err)]
+        let echo = || -> tungstenite::Result<()> {
+            stream.set_nonblocking(false)?;
+            // wake up now and then to see whether we should stop
+            stream.set_read_timeout(Some(Self::POLL_INTERVAL))?;
+            let mut socket = tungstenite::accept(stream).map_err(|e| match e {
+                HandshakeError::Failure(e) => e,
+                HandshakeError::Interrupted(_) => std::io::Error::from(ErrorKind::TimedOut).into(),
+            })?;
+            while !stop.load(std::sync::atomic::Ordering::Relaxed) {
+                match socket.read() {
+                    // tungstenite answers pings and close frames itself
+                    Ok(msg @ (Message::Binary(_) | Message::Text(_))) => socket.send(msg)?,
+                    Ok(_) => {}
+                    Err(tungstenite::Error::Io(e))
+                        if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+                    {
+                        // flush whatever a read timeout left pending, e.g. pongs
+                        socket.flush()?;
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            socket.close(None)?;
+            socket.flush()
+        };
+        match echo() {
+            Ok(()) | Err(tungstenite::Error::ConnectionClosed) => {}
+            Err(e) => debug!("Local echo server connection ended: {e}"),
+        }
+    }
+}
+
+impl Drop for LocalEchoServer {
+    fn drop(&mut self) {
+        self.stop.store(true, std::sync::atomic::Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use tungstenite::http::{header::AUTHORIZATION, HeaderValue};
+
+    use super::*;
+    use crate::{
+        testing::{app, connected_app, drain, echo, update_until},
+        CloseCode, ConnectionClosed, Disconnect, SendText, WebSocketConnectionEvents,
+        WebSocketMessageReceived,
+    };
+
+    #[test]
+    fn local_echo_server_round_trips() {
+        let (mut app, connection) = connected_app();
+        echo(&mut app, connection, &[0, 1, 2, 255]);
+        app.world_mut().send_event(SendText {
+            entity: connection,
+            text: "hi".to_owned(),
+        });
+        let text = update_until(&mut app, |app| drain::<WebSocketMessageReceived>(app).pop());
+        assert_eq!((text.data.as_slice(), text.text), (&b"hi"[..], true));
+
+        app.world_mut()
+            .send_event(WebSocketConnectionEvents::Reconnect(connection));
+        update_until(&mut app, |app| drain::<ConnectionOpened>(app).pop());
+        echo(&mut app, connection, b"again");
+
+        app.world_mut().send_event(Disconnect {
+            entity: connection,
+            code: CloseCode::Normal,
+            reason: "done".to_owned(),
+        });
+        update_until(&mut app, |app| drain::<ConnectionClosed>(app).pop());
+        // joins the server's threads, hanging the test if any of them leaked
+        app.world_mut().remove_resource::<LocalEchoServer>();
+    }
+
+    /// An app running a [`WebSocketServer`] on an ephemeral port, and that port's address
+    fn server_app() -> (App, SocketAddr) {
+        let server = WebSocketServer::bind("127.0.0.1:0").unwrap();
+        let addr = server.listener.local_addr().unwrap();
+        let mut app = app(Duration::from_millis(10));
+        app.insert_resource(server);
+        (app, addr)
+    }
+
+    /// Send a raw upgrade request to the server in `app`, returning the response head
+    fn raw_upgrade(app: &mut App, addr: SocketAddr, headers: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(format!("GET / HTTP/1.1\r\nHost: {addr}\r\n{headers}\r\n").as_bytes())
+            .unwrap();
+        stream.set_nonblocking(true).unwrap();
+        let mut response = Vec::new();
+        update_until(app, |_| {
+            let mut buf = [0; 1024];
+            match stream.read(&mut buf) {
+                Ok(n) => response.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => panic!("{e}"),
+            }
+            let response = String::from_utf8_lossy(&response);
+            response
+                .find("\r\n\r\n")
+                .map(|end| response[..end].to_owned())
+        })
+    }
+
+    /// Upgrade headers of the example in RFC 6455, section 1.3
+    const VALID_UPGRADE: &str = "Upgrade: websocket\r\nConnection: Upgrade\r\n\
+        Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n";
+
+    #[test]
+    #[allow(clippy::result_large_err)]
+    fn server_handshakes_accept_valid_upgrades_and_reject_malformed_ones() {
+        let (mut app, addr) = server_app();
+        app.insert_resource(HandshakeHook::new(|_, mut response| {
+            let value = HeaderValue::from_static("yes");
+            response.headers_mut().insert("x-hooked", value);
+            Ok(response)
+        }));
+        let response = raw_upgrade(&mut app, addr, VALID_UPGRADE).to_ascii_lowercase();
+        assert!(response.starts_with("http/1.1 101"), "{response}");
+        assert!(response.contains("sec-websocket-accept: s3pplmbitxaq9kygzzhzrbk+xoo="));
+        assert!(response.contains("x-hooked: yes"));
+        update_until(&mut app, |app| drain::<ConnectionOpened>(app).pop());
+
+        let no_key = "Upgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Version: 13\r\n";
+        let response = raw_upgrade(&mut app, addr, no_key);
+        assert!(response.starts_with("HTTP/1.1 400"), "{response}");
+        let rejected = update_until(&mut app, |app| drain::<ConnectionRejected>(app).pop());
+        assert_eq!(rejected.status, 400);
+    }
+
+    #[test]
+    fn servers_turn_away_unauthorized_peers() {
+        let (mut app, addr) = server_app();
+        app.insert_resource(ConnectionAuthorizer::new(|request| {
+            match request.headers().get(AUTHORIZATION) {
+                Some(token) if token == "Bearer letmein" => Ok(()),
+                _ => Err(401),
+            }
+        }));
+        let response = raw_upgrade(&mut app, addr, VALID_UPGRADE);
+        assert!(response.starts_with("HTTP/1.1 401"), "{response}");
+        let rejected = update_until(&mut app, |app| drain::<ConnectionRejected>(app).pop());
+        assert_eq!(rejected.status, 401);
+        let mut peers = app.world_mut().query::<&WebSocketClient>();
+        assert_eq!(peers.iter(app.world()).count(), 0);
+
+        let authorized = format!("{VALID_UPGRADE}Authorization: Bearer letmein\r\n");
+        let response = raw_upgrade(&mut app, addr, &authorized);
+        assert!(response.starts_with("HTTP/1.1 101"), "{response}");
+        update_until(&mut app, |app| drain::<ConnectionOpened>(app).pop());
+        assert_eq!(peers.iter(app.world()).count(), 1);
+    }
+}
@@ -0,0 +1,192 @@
+//! Helpers shared by the tests of every module
+
+use std::{
+    io::Read,
+    net::{SocketAddr, TcpListener, TcpStream},
+    time::Duration,
+};
+
+use bevy::{prelude::*, time::TimeUpdateStrategy};
+use tungstenite::Message;
+
+use crate::{
+    decode_transforms, ConnectionOpened, LocalEchoServer, MessageSendOutcome, NetworkId, Outbox,
+    ReplicaRegistry, SendOutcome, SendTo, WebSocketConfig, WebSocketConnectionEvents,
+    WebSocketMessageReceived, WebSocketPlugin, WireFormat, TRANSFORM_DELTA_TAG,
+};
+
+/// The plugin on a headless app whose clock advances by `step` every update
+pub(crate) fn app(step: Duration) -> App {
+    let mut app = App::new();
+    app.add_plugins((MinimalPlugins, WebSocketPlugin::default()))
+        .insert_resource(TimeUpdateStrategy::ManualDuration(step));
+    app
+}
+
+pub(crate) fn replica(app: &App, id: NetworkId) -> Option<Entity> {
+    app.world().resource::<ReplicaRegistry>().get(id)
+}
+
+/// Update until `done` returns something, failing the test after a few seconds
+pub(crate) fn update_until<T>(app: &mut App, mut done: impl FnMut(&mut App) -> Option<T>) -> T {
+    let deadline = std::time::Instant::now() + Duration::from_secs(10);
+    loop {
+        app.update();
+        if let Some(result) = done(app) {
+            return result;
+        }
+        assert!(std::time::Instant::now() < deadline, "timed out");
+        std::thread::sleep(Duration::from_millis(1));
+    }
+}
+
+/// Events of type `E` sent since the last call
+pub(crate) fn drain<E: Event>(app: &mut App) -> Vec<E> {
+    app.world_mut()
+        .resource_mut::<Events<E>>()
+        .drain()
+        .collect()
+}
+
+/// An app connected to a fresh [`LocalEchoServer`], and the connection
+pub(crate) fn connected_app() -> (App, Entity) {
+    let server = LocalEchoServer::start().unwrap();
+    let mut app = app(Duration::from_millis(10));
+    app.world_mut().resource_mut::<WebSocketConfig>().url = server.url().to_owned();
+    app.insert_resource(server);
+    let connection = connect(&mut app);
+    (app, connection)
+}
+
+/// Open another connection to [`WebSocketConfig::url`]
+pub(crate) fn connect(app: &mut App) -> Entity {
+    app.world_mut()
+        .send_event(WebSocketConnectionEvents::SetupConnection(None));
+    update_until(app, |app| {
+        drain::<ConnectionOpened>(app)
+            .first()
+            .map(|opened| opened.entity)
+    })
+}
+
+pub(crate) fn send_outcome(app: &mut App, entity: Entity) -> SendOutcome {
+    app.world_mut().send_event(SendTo {
+        entity,
+        data: b"hello".to_vec(),
+    });
+    update_until(app, |app| {
+        drain::<MessageSendOutcome>(app)
+            .into_iter()
+            .find(|outcome| outcome.bytes == 5)
+            .map(|outcome| outcome.outcome)
+    })
+}
+
+/// Bytes that don't compress, from a xorshift so the tests stay deterministic
+pub(crate) fn noise(len: usize) -> Vec<u8> {
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+/// A URL nothing listens on, since we just gave its port back, and its address
+pub(crate) fn refused_url() -> (String, SocketAddr) {
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .unwrap();
+    (format!("ws://{addr}/"), addr)
+}
+
+pub(crate) fn echo(app: &mut App, entity: Entity, data: &[u8]) {
+    app.world_mut().send_event(SendTo {
+        entity,
+        data: data.to_vec(),
+    });
+    update_until(app, |app| {
+        let received = drain::<WebSocketMessageReceived>(app);
+        received.into_iter().find(|received| received.data == data)
+    });
+}
+
+/// A server for one connection that reports the close frame it receives. If it's to
+/// `ignore_close` it never answers and returns once the client hangs up.
+pub(crate) fn close_frame_server(
+    ignore_close: bool,
+) -> (
+    String,
+    std::thread::JoinHandle<Option<tungstenite::protocol::CloseFrame<'static>>>,
+) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("ws://{}/", listener.local_addr().unwrap());
+    let server = std::thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut socket = tungstenite::accept(stream).unwrap();
+        loop {
+            match socket.read() {
+                Ok(Message::Close(frame)) if ignore_close => {
+                    // straight from the stream, so tungstenite doesn't send its answer
+                    while socket.get_mut().read(&mut [0; 64]).is_ok_and(|n| n > 0) {}
+                    return frame.map(|frame| frame.into_owned());
+                }
+                Ok(Message::Close(frame)) => {
+                    let _ = socket.flush();
+                    return frame.map(|frame| frame.into_owned());
+                }
+                Ok(_) => {}
+                Err(_) => return None,
+            }
+        }
+    });
+    (url, server)
+}
+
+/// Read one raw websocket frame (fin, opcode, unmasked payload) off a client connection,
+/// `None` once it hung up
+pub(crate) fn read_raw_frame(stream: &mut TcpStream) -> Option<(bool, u8, Vec<u8>)> {
+    let mut header = [0; 2];
+    stream.read_exact(&mut header).ok()?;
+    let len = match header[1] & 0x7f {
+        126 => {
+            let mut len = [0; 2];
+            stream.read_exact(&mut len).ok()?;
+            u64::from(u16::from_be_bytes(len))
+        }
+        127 => {
+            let mut len = [0; 8];
+            stream.read_exact(&mut len).ok()?;
+            u64::from_be_bytes(len)
+        }
+        len => u64::from(len),
+    };
+    // clients always mask
+    let mut mask = [0; 4];
+    stream.read_exact(&mut mask).ok()?;
+    let mut payload = vec![0; len as usize];
+    stream.read_exact(&mut payload).ok()?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    Some((header[0] & 0x80 != 0, header[0] & 0x0f, payload))
+}
+
+/// The transforms in every message waiting in `entity`'s outbox
+pub(crate) fn queued_transforms(
+    app: &App,
+    entity: Entity,
+) -> Vec<(bool, Vec<(NetworkId, Transform)>)> {
+    let outbox = app.world().get::<Outbox>(entity).unwrap();
+    outbox
+        .queue
+        .iter()
+        .filter_map(|msg| {
+            let delta = msg.data.starts_with(&TRANSFORM_DELTA_TAG);
+            Some((delta, decode_transforms(WireFormat::Bincode, &msg.data)?))
+        })
+        .collect()
+}
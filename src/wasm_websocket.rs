@@ -0,0 +1,191 @@
+//! The browser's `WebSocket` API, wrapped for the wasm transport.
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::VecDeque,
+    rc::Rc,
+};
+
+use crate::{message_buffer, text_message, RecvOverflowPolicy};
+use bevy::{
+    ecs::system::Resource,
+    log::{info, warn},
+};
+
+use web_sys::{
+    js_sys::Date,
+    js_sys::{ArrayBuffer, JsString, Uint8Array},
+    wasm_bindgen::{prelude::Closure, JsCast, JsValue},
+    BinaryType, CloseEvent, Event, MessageEvent,
+};
+
+/// Bounds the frames buffered between the browser's message callback and `recv_info`,
+/// so a fast server can't grow memory without limit while the app is slow to process
+#[derive(Resource, Clone)]
+pub struct RecvQueueLimit {
+    pub max_depth: usize,
+    pub policy: RecvOverflowPolicy,
+    /// Drop messages bigger than this before copying them out of the browser, like
+    /// `SizeLimits::max_message_size` does natively
+    pub max_message_size: usize,
+}
+
+impl Default for RecvQueueLimit {
+    fn default() -> Self {
+        Self {
+            max_depth: 1024,
+            policy: RecvOverflowPolicy::DropOldest,
+            // same as `SizeLimits` natively
+            max_message_size: 1 << 20,
+        }
+    }
+}
+
+/// Copy a received message into a `Vec`, `None` if it's too big for `max_size` or the
+/// allocation fails
+fn copy_message(buf: &ArrayBuffer, max_size: usize) -> Option<Vec<u8>> {
+    let mut data = message_buffer(buf.byte_length() as usize, max_size)?;
+    Uint8Array::new(buf).copy_to(&mut data);
+    Some(data)
+}
+
+/// Received frames and whether they were text messages
+pub type RecvQueue = Rc<RefCell<VecDeque<(Vec<u8>, bool)>>>;
+
+pub struct Client {
+    pub socket: web_sys::WebSocket,
+    pub recv_queue: RecvQueue,
+    /// Set by the open callback until the ECS has been told about it
+    pub open_pending: Rc<Cell<bool>>,
+    /// Frames dropped because the receive queue was full, since `recv_info` last looked
+    pub dropped: Rc<Cell<usize>>,
+    /// Set by the close callback to why the socket closed, until the ECS has been told
+    pub closed: Rc<RefCell<Option<String>>>,
+    /// Whether the error callback fired, browsers don't say more than that
+    pub errored: Rc<Cell<bool>>,
+    /// `Date.now()` when we started connecting
+    pub started_ms: f64,
+    open_cb: Closure<dyn FnMut(Event)>,
+    message_cb: Closure<dyn FnMut(MessageEvent)>,
+    error_cb: Closure<dyn FnMut(Event)>,
+    close_cb: Closure<dyn FnMut(CloseEvent)>,
+}
+
+impl Client {
+    pub fn new(url: &str, limit: RecvQueueLimit) -> send_wrapper::SendWrapper<Self> {
+        info!("Opening wasm websocket");
+        let recv_queue = Rc::new(RefCell::new(VecDeque::new()));
+        let open_pending = Rc::new(Cell::new(false));
+        let dropped = Rc::new(Cell::new(0));
+        let closed = Rc::new(RefCell::new(None));
+        let errored = Rc::new(Cell::new(false));
+        let started_ms = Date::now();
+        let socket = web_sys::WebSocket::new(url).expect("Failed to create WebSocket object");
+        socket.set_binary_type(BinaryType::Arraybuffer);
+        let open_cb: Closure<dyn FnMut(_)> = Closure::new({
+            let open_pending = Rc::clone(&open_pending);
+            move |_event: Event| {
+                web_sys::console::log_1(&"Connection opened".into());
+                open_pending.set(true);
+            }
+        });
+        socket
+            .add_event_listener_with_callback("open", open_cb.as_ref().dyn_ref().unwrap())
+            .unwrap();
+        let message_cb: Closure<dyn FnMut(_)> = Closure::new({
+            let recv_queue = Rc::clone(&recv_queue);
+            let dropped = Rc::clone(&dropped);
+            move |event: MessageEvent| {
+                web_sys::console::log_1(&format!("Got message: {:?}", event.data()).into());
+                // text messages are handed on as their UTF-8 bytes
+                let data = if let Some(buf) = event.data().dyn_ref::<ArrayBuffer>() {
+                    match copy_message(buf, limit.max_message_size) {
+                        Some(data) => (data, false),
+                        None => return,
+                    }
+                } else if let Some(text) = event.data().dyn_ref::<JsString>() {
+                    // UTF-16 code units, each at least one byte of UTF-8, so this can
+                    // only rule out the ones that are too long without converting
+                    let len = text.length() as usize;
+                    if len > limit.max_message_size {
+                        let max = limit.max_message_size;
+                        warn!("Dropping a {len} character text message, the limit is {max}");
+                        return;
+                    }
+                    match text_message(String::from(text), limit.max_message_size) {
+                        Some(data) => (data, true),
+                        None => return,
+                    }
+                } else {
+                    return;
+                };
+                let mut recv_queue = recv_queue.borrow_mut();
+                if limit.policy.push(&mut recv_queue, data, limit.max_depth) {
+                    dropped.set(dropped.get() + 1);
+                }
+            }
+        });
+        socket
+            .add_event_listener_with_callback("message", message_cb.as_ref().dyn_ref().unwrap())
+            .unwrap();
+        let error_cb: Closure<dyn FnMut(_)> = Closure::new({
+            let errored = Rc::clone(&errored);
+            move |_event: Event| {
+                web_sys::console::log_1(&"Connection error".into());
+                errored.set(true);
+            }
+        });
+        socket
+            .add_event_listener_with_callback("error", error_cb.as_ref().dyn_ref().unwrap())
+            .unwrap();
+        let close_cb: Closure<dyn FnMut(_)> = Closure::new({
+            let closed = Rc::clone(&closed);
+            move |event: CloseEvent| {
+                let reason = match event.reason() {
+                    reason if reason.is_empty() => format!("close code {}", event.code()),
+                    reason => format!("close code {}: {reason}", event.code()),
+                };
+                web_sys::console::log_1(&format!("Connection closed ({reason})").into());
+                *closed.borrow_mut() = Some(reason);
+            }
+        });
+        socket
+            .add_event_listener_with_callback("close", close_cb.as_ref().dyn_ref().unwrap())
+            .unwrap();
+        send_wrapper::SendWrapper::new(Client {
+            socket,
+            recv_queue,
+            open_pending,
+            dropped,
+            closed,
+            errored,
+            started_ms,
+            open_cb,
+            message_cb,
+            error_cb,
+            close_cb,
+        })
+    }
+
+    /// Whether the socket finished connecting and takes messages, until it starts closing
+    pub fn is_open(&self) -> bool {
+        self.socket.ready_state() == web_sys::WebSocket::OPEN
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        // the socket outlives us while it's closing, and calling a dropped closure throws
+        let listeners: [(&str, &JsValue); 4] = [
+            ("open", self.open_cb.as_ref()),
+            ("message", self.message_cb.as_ref()),
+            ("error", self.error_cb.as_ref()),
+            ("close", self.close_cb.as_ref()),
+        ];
+        for (event, listener) in listeners {
+            let _ = self
+                .socket
+                .remove_event_listener_with_callback(event, listener.unchecked_ref());
+        }
+    }
+}
@@ -0,0 +1,300 @@
+//! How messages are encoded: [`WireFormat`]s and [`Multiplexed`] channels.
+
+use bevy::prelude::*;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::Outbox;
+
+/// Carry independent streams (e.g. chat and game state) over one connection.
+///
+/// Every frame starts with the [`Channel`] it belongs to and received messages report it,
+/// so apps can pick the channels they care about. All frames share one socket,
+/// so messages stay in order within each channel. Both peers need this component.
+#[derive(Component)]
+pub struct Multiplexed;
+
+pub type Channel = u8;
+
+/// The channel `send_info` puts its transforms on
+pub const GAME_STATE_CHANNEL: Channel = 0;
+
+/// How a connection serializes its messages. New connections start with
+/// [`WebSocketConfig::format`](crate::WebSocketConfig::format), connections without the component
+/// use bincode.
+///
+/// Protocols that negotiate in JSON and then upgrade to bincode can switch with
+/// [`SwitchWireFormat`].
+///
+/// Typed messages go out as bytes encoded with the connection's format and are decoded the
+/// same way on arrival:
+///
+/// ```
+/// # use std::time::Duration;
+/// # use bevy::prelude::*;
+/// # use bevy_websocket::*;
+/// # use serde::{Deserialize, Serialize};
+/// #[derive(Serialize, Deserialize, PartialEq, Debug)]
+/// struct Chat {
+///     from: String,
+///     text: String,
+/// }
+///
+/// let server = LocalEchoServer::start()?;
+/// let mut app = App::new();
+/// app.add_plugins((MinimalPlugins, WebSocketPlugin::default().with_url(server.url())))
+///     .insert_resource(server)
+///     .observe(|opened: Trigger<ConnectionOpened>, formats: Query<&WireFormat>,
+///               mut ev_send: EventWriter<SendTo>| {
+///         let entity = opened.event().entity;
+///         let format = formats.get(entity).copied().unwrap_or_default();
+///         let chat = Chat { from: "me".to_owned(), text: "hi".to_owned() };
+///         ev_send.send(SendTo { entity, data: format.encode(&chat) });
+///     })
+///     .add_systems(Update, |mut ev_received: EventReader<WebSocketMessageReceived>,
+///                           formats: Query<&WireFormat>, mut ev_exit: EventWriter<AppExit>| {
+///         for received in ev_received.read() {
+///             let format = formats.get(received.entity).copied().unwrap_or_default();
+///             let chat = format.decode::<Chat>(&received.data).expect("not a chat message");
+///             assert_eq!(chat, Chat { from: "me".to_owned(), text: "hi".to_owned() });
+///             ev_exit.send(AppExit::Success);
+///         }
+///     });
+/// # app.add_systems(Update, |time: Res<Time<Real>>| {
+/// #     assert!(time.elapsed() < Duration::from_secs(10), "no round trip");
+/// # });
+/// app.world_mut().send_event(WebSocketConnectionEvents::SetupConnection(None));
+/// app.run();
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Component, Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub enum WireFormat {
+    #[default]
+    Bincode,
+    Json,
+}
+
+impl WireFormat {
+    pub fn encode<T: Serialize>(self, value: &T) -> Vec<u8> {
+        match self {
+            WireFormat::Bincode => bincode::serialize(value).unwrap(),
+            WireFormat::Json => serde_json::to_vec(value).unwrap(),
+        }
+    }
+
+    /// Like [`WireFormat::encode`], but appending to `buf`
+    pub(crate) fn encode_into<T: Serialize>(self, value: &T, buf: &mut Vec<u8>) {
+        match self {
+            WireFormat::Bincode => bincode::serialize_into(buf, value).unwrap(),
+            WireFormat::Json => serde_json::to_writer(buf, value).unwrap(),
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(self, data: &[u8]) -> Option<T> {
+        match self {
+            WireFormat::Bincode => bincode::deserialize(data).ok(),
+            WireFormat::Json => serde_json::from_slice(data).ok(),
+        }
+    }
+}
+
+/// Scratch space `send_info` encodes into, so a connection's buffer only grows until it
+/// fits its largest state and is reused from then on. The outbox gets an exactly sized copy,
+/// so JSON in particular doesn't regrow its output every time.
+#[derive(Component, Default)]
+pub struct SerializeBuffer(pub(crate) Vec<u8>);
+
+/// Change a connection's [`WireFormat`].
+///
+/// The switch waits until everything already queued in the old format was written,
+/// and messages received in the same frame are still decoded the old way.
+#[derive(Event)]
+pub struct SwitchWireFormat {
+    pub entity: Entity,
+    pub format: WireFormat,
+}
+
+/// A [`SwitchWireFormat`] waiting for the [`Outbox`] to drain
+#[derive(Component)]
+pub struct PendingWireFormat(WireFormat);
+
+pub fn switch_wire_formats(mut commands: Commands, mut ev_switch: EventReader<SwitchWireFormat>) {
+    for SwitchWireFormat { entity, format } in ev_switch.read() {
+        if let Some(mut entity_commands) = commands.get_entity(*entity) {
+            entity_commands.insert(PendingWireFormat(*format));
+        }
+    }
+}
+
+pub fn apply_pending_wire_formats(
+    mut commands: Commands,
+    pending: Query<(Entity, &PendingWireFormat, Option<&Outbox>)>,
+) {
+    for (entity, PendingWireFormat(format), outbox) in &pending {
+        if outbox.is_some_and(|outbox| !outbox.queue.is_empty()) {
+            continue; // flush the old format first
+        }
+        info!("Switching {entity} to {format:?}");
+        commands
+            .entity(entity)
+            .insert(*format)
+            .remove::<PendingWireFormat>();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        decode_transforms,
+        testing::{app, connected_app, drain, replica, update_until},
+        InjectInboundMessage, NetworkId, OutboxMemoryBudget, ReplicateTransforms,
+        WebSocketMessageReceived,
+    };
+
+    #[test]
+    fn multiplexed_channels_are_demultiplexed_in_order() {
+        let (mut app, connection) = connected_app();
+        app.world_mut().entity_mut(connection).insert(Multiplexed);
+        let sent: [(Channel, &[u8]); 4] = [(1, b"hi"), (2, b"jump"), (1, b"bye"), (2, b"")];
+        app.world_mut()
+            .resource_scope(|world, mut budget: Mut<OutboxMemoryBudget>| {
+                let mut outbox = world.get_mut::<Outbox>(connection).unwrap();
+                for (channel, msg) in sent {
+                    outbox.send_on(channel, msg, &mut budget).unwrap();
+                }
+            });
+        let mut received = Vec::new();
+        update_until(&mut app, |app| {
+            received.extend(drain::<WebSocketMessageReceived>(app));
+            (received.len() >= sent.len()).then_some(())
+        });
+        let received: Vec<_> = received
+            .iter()
+            .map(|received| (received.channel, received.data.as_slice()))
+            .collect();
+        let sent: Vec<_> = sent.into_iter().map(|(c, msg)| (Some(c), msg)).collect();
+        assert_eq!(received, sent);
+
+        // transforms only count on the game state channel
+        let transforms =
+            |id| WireFormat::Bincode.encode(&vec![(NetworkId(id), Transform::IDENTITY)]);
+        for (channel, id) in [(1, 1), (GAME_STATE_CHANNEL, 2)] {
+            app.world_mut().send_event(InjectInboundMessage {
+                entity: connection,
+                data: [&[channel][..], &transforms(id)].concat(),
+            });
+        }
+        app.update();
+        app.update();
+        assert_eq!(replica(&app, NetworkId(1)), None);
+        assert!(replica(&app, NetworkId(2)).is_some());
+    }
+
+    #[test]
+    fn wire_format_switches_wait_for_the_outbox_to_drain() {
+        let mut app = app(Duration::from_millis(100));
+        let connection = app
+            .world_mut()
+            .spawn((Outbox::default(), ReplicateTransforms, WireFormat::Bincode))
+            .id();
+        app.world_mut().spawn((Transform::IDENTITY, NetworkId(1)));
+        let state = vec![(NetworkId(1), Transform::IDENTITY)];
+        let queued = |app: &App| -> Vec<Vec<u8>> {
+            let outbox = app.world().get::<Outbox>(connection).unwrap();
+            outbox.queue.iter().map(|msg| msg.data.clone()).collect()
+        };
+        update_until(&mut app, |app| (!queued(app).is_empty()).then_some(()));
+        app.world_mut().send_event(SwitchWireFormat {
+            entity: connection,
+            format: WireFormat::Json,
+        });
+        for _ in 0..3 {
+            app.update();
+        }
+        // the bincode message is still waiting
+        assert_eq!(
+            app.world().get::<WireFormat>(connection),
+            Some(&WireFormat::Bincode)
+        );
+        assert!(app.world().get::<PendingWireFormat>(connection).is_some());
+
+        // as if it was written
+        let written =
+            app.world_mut()
+                .resource_scope(|world, mut budget: Mut<OutboxMemoryBudget>| {
+                    let mut outbox = world.get_mut::<Outbox>(connection).unwrap();
+                    let written: Vec<_> = outbox.queue.drain(..).map(|msg| msg.data).collect();
+                    outbox.release(written.iter().map(Vec::len).sum(), &mut budget);
+                    written
+                });
+        assert!(written
+            .iter()
+            .all(|msg| decode_transforms(WireFormat::Bincode, msg) == Some(state.clone())));
+        app.update();
+        assert_eq!(
+            app.world().get::<WireFormat>(connection),
+            Some(&WireFormat::Json)
+        );
+        let next = update_until(&mut app, |app| queued(app).pop());
+        assert_eq!(decode_transforms(WireFormat::Json, &next), Some(state));
+    }
+
+    #[test]
+    fn reused_serialize_buffers_encode_like_fresh_ones() {
+        for format in [WireFormat::Bincode, WireFormat::Json] {
+            let mut app = app(Duration::from_millis(100));
+            let moving = app
+                .world_mut()
+                .spawn((Transform::from_xyz(1.0, 2.0, 3.0), NetworkId(1)))
+                .id();
+            app.world_mut().spawn((Transform::IDENTITY, NetworkId(2)));
+            let [fresh, reused] = [false, true].map(|buffered| {
+                let mut connection =
+                    app.world_mut()
+                        .spawn((Outbox::default(), ReplicateTransforms, format));
+                if buffered {
+                    connection.insert(SerializeBuffer::default());
+                }
+                connection.id()
+            });
+            let queued = |app: &App, entity| {
+                let outbox = app.world().get::<Outbox>(entity).unwrap();
+                outbox.queued().map(<[u8]>::to_vec).collect::<Vec<_>>()
+            };
+            update_until(&mut app, |app| {
+                (queued(app, fresh).len() == 1).then_some(())
+            });
+            // the second send encodes into the buffer the first one left behind
+            app.world_mut()
+                .entity_mut(moving)
+                .insert(Transform::IDENTITY);
+            update_until(&mut app, |app| {
+                (queued(app, fresh).len() == 2).then_some(())
+            });
+            assert_eq!(queued(&app, reused), queued(&app, fresh));
+        }
+    }
+
+    #[test]
+    fn transforms_round_trip_through_every_wire_format() {
+        let transforms = vec![
+            Transform::from_xyz(1.0, -2.5, 3.0),
+            Transform::from_rotation(Quat::from_rotation_y(1.0)).with_scale(Vec3::splat(2.0)),
+        ];
+        for format in [WireFormat::Bincode, WireFormat::Json] {
+            let encoded = format.encode(&transforms);
+            assert_eq!(
+                format.decode::<Vec<Transform>>(&encoded),
+                Some(transforms.clone())
+            );
+            assert_eq!(format.decode::<Vec<Transform>>(b"\xff garbage"), None);
+        }
+        // readable in the browser's devtools
+        let json = WireFormat::Json.encode(&transforms);
+        assert!(std::str::from_utf8(&json)
+            .unwrap()
+            .contains("\"translation\""));
+    }
+}